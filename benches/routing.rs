@@ -0,0 +1,174 @@
+// Frame routing and slave selection under load, the paths the balancer
+// snapshots and per-session channels are meant to keep lock-free.
+//
+//   cargo bench --bench routing
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use net_relay::proxy::{Client, ClientEvent, ProxyManager, Slave};
+use net_relay::routing::RouteRequest;
+use net_relay::tls::ClientStream;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, Mutex};
+
+const SLAVES: u32 = 8;
+const SESSIONS: u32 = 512;
+const FRAMES: usize = 64;
+const CHUNK: usize = 1024;
+
+async fn slave(location: &str) -> (Slave, mpsc::Receiver<Bytes>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (mut slave, rx) = Slave::new("127.0.0.1".to_string(), stream);
+    slave.set_location(location.to_string());
+    slave.set_speed(10.0);
+    (slave, rx)
+}
+
+async fn manager_with_slaves() -> (Arc<ProxyManager>, Vec<mpsc::Receiver<Bytes>>) {
+    let manager = Arc::new(ProxyManager::new(2, Duration::from_secs(60)));
+    let mut receivers = Vec::new();
+    for _ in 0..SLAVES {
+        let (slave, rx) = slave("US").await;
+        manager.add_slave(slave);
+        receivers.push(rx);
+    }
+    (manager, receivers)
+}
+
+// Every session gets FRAMES data frames, routed by one task per slave
+// connection while each session drains its own channel
+async fn route_round(manager: &Arc<ProxyManager>, stream: &Arc<Mutex<ClientStream>>, src_addr: SocketAddr) -> Duration {
+    let mut drains = Vec::new();
+    for session_id in 0..SESSIONS {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager
+            .clients
+            .insert(session_id, Client::new(Arc::clone(stream), src_addr, tx));
+        drains.push(tokio::spawn(async move {
+            let mut received = 0;
+            while received < FRAMES {
+                match rx.recv().await {
+                    Some(ClientEvent::Data(_)) => received += 1,
+                    _ => break,
+                }
+            }
+            received
+        }));
+    }
+
+    let started = Instant::now();
+    let chunk = Bytes::from(vec![0u8; CHUNK]);
+    let mut routers = Vec::new();
+    for slave in 0..SLAVES {
+        let manager = Arc::clone(manager);
+        let chunk = chunk.clone();
+        routers.push(tokio::spawn(async move {
+            for frame in 0..FRAMES {
+                for session_id in (slave..SESSIONS).step_by(SLAVES as usize) {
+                    manager.route_to_client(session_id, ClientEvent::Data(chunk.clone()));
+                }
+                if frame % 16 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        }));
+    }
+    for router in routers {
+        router.await.unwrap();
+    }
+    let mut delivered = 0;
+    for drain in drains {
+        delivered += drain.await.unwrap();
+    }
+    let elapsed = started.elapsed();
+
+    assert_eq!(delivered, SESSIONS as usize * FRAMES);
+    manager.clients.clear();
+    elapsed
+}
+
+fn routing(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+    let (manager, _receivers) = runtime.block_on(manager_with_slaves());
+
+    // Routing never touches the client socket, so the sessions share one
+    let (stream, src_addr) = runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let src_addr = stream.local_addr().unwrap();
+        (Arc::new(Mutex::new(ClientStream::from(stream))), src_addr)
+    });
+
+    let mut group = c.benchmark_group("routing");
+    group.throughput(Throughput::Bytes((SESSIONS as usize * FRAMES * CHUNK) as u64));
+    group.bench_function("route_to_client", |b| {
+        b.to_async(&runtime).iter_custom(|iters| {
+            let manager = Arc::clone(&manager);
+            let stream = Arc::clone(&stream);
+            async move {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    elapsed += route_round(&manager, &stream, src_addr).await;
+                }
+                elapsed
+            }
+        })
+    });
+    group.finish();
+}
+
+fn selection(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (manager, _receivers) = runtime.block_on(manager_with_slaves());
+    let route = RouteRequest::default();
+    let mut group = c.benchmark_group("selection");
+
+    let mut i = 0u32;
+    group.bench_function("get_available_slave", |b| {
+        b.iter(|| {
+            i = i.wrapping_add(1);
+            let client_addr = SocketAddr::from(([198, 51, 100, (i % 250) as u8], 40000));
+            manager.get_available_slave(&client_addr, None, &route, &[]).unwrap()
+        })
+    });
+
+    // Slaves keep joining and leaving while sessions select
+    let churning = Arc::new(AtomicBool::new(true));
+    let churn = runtime.spawn({
+        let manager = Arc::clone(&manager);
+        let churning = Arc::clone(&churning);
+        async move {
+            while churning.load(Ordering::Relaxed) {
+                let (slave, _rx) = slave("DE").await;
+                let slave = manager.add_slave(slave);
+                tokio::task::yield_now().await;
+                manager.remove_slave(&slave);
+            }
+        }
+    });
+    // A selection can land on the slave that is just leaving, which fails the
+    // same way it would for a real session
+    group.bench_function("get_available_slave_during_churn", |b| {
+        b.iter(|| {
+            i = i.wrapping_add(1);
+            let client_addr = SocketAddr::from(([198, 51, 100, (i % 250) as u8], 40000));
+            manager.get_available_slave(&client_addr, None, &route, &[]).is_ok()
+        })
+    });
+    churning.store(false, Ordering::Relaxed);
+    runtime.block_on(churn).unwrap();
+    group.finish();
+}
+
+criterion_group!(benches, routing, selection);
+criterion_main!(benches);
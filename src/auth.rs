@@ -0,0 +1,334 @@
+use log::{debug, error, info, warn};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::sync::Semaphore;
+use tokio::time::{interval, Duration};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+// PBKDF2-HMAC-SHA256 iterations for new password hashes
+pub const PBKDF2_ROUNDS: u32 = 600_000;
+// Password checks running at once, so login attempts can't take every core
+const MAX_CONCURRENT_HASHES: usize = 4;
+// Salt of the hash unknown usernames are checked against
+const DUMMY_SALT: &str = "00000000000000000000000000000000";
+
+// An authenticated SOCKS5 user and the limits that apply to its sessions
+#[derive(Debug, Clone, Default)]
+pub struct User {
+    pub username: String,
+    // Slave countries this user may be routed through, empty means any
+    pub allowed_countries: Vec<String>,
+    // Maximum number of concurrent sessions, None means unlimited
+    pub max_sessions: Option<usize>,
+}
+
+// Hook for plugging in a credential backend
+pub trait CredentialStore: Send + Sync {
+    fn authenticate(&self, username: &str, password: &str) -> Option<User>;
+}
+
+// Which SOCKS5 authentication methods clients may use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthPolicy {
+    // Username/password only
+    Required,
+    // No authentication only, credentials are never asked for
+    NoAuth,
+    // Either, username/password preferred when offered
+    Optional,
+}
+
+// Authentication settings shared by the client listeners
+pub struct ClientAuth {
+    pub policy: AuthPolicy,
+    pub credentials: Option<Arc<dyn CredentialStore>>,
+    hashing: Semaphore,
+}
+
+impl ClientAuth {
+    pub fn new(policy: AuthPolicy, credentials: Option<Arc<dyn CredentialStore>>) -> Self {
+        Self {
+            policy,
+            credentials,
+            hashing: Semaphore::new(MAX_CONCURRENT_HASHES),
+        }
+    }
+
+    // Without a credential backend any user is accepted without limits.
+    // Password hashing is deliberately slow, so it runs off the async workers
+    // and only a few checks run at a time.
+    pub async fn authenticate(&self, username: String, password: &str) -> Option<User> {
+        match &self.credentials {
+            Some(store) => {
+                let _permit = self.hashing.acquire().await.ok()?;
+                let store = Arc::clone(store);
+                let password = password.to_string();
+                tokio::task::spawn_blocking(move || store.authenticate(&username, &password))
+                    .await
+                    .ok()
+                    .flatten()
+            }
+            None => Some(User {
+                username,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+struct UserEntry {
+    hash: PasswordHash,
+    user: User,
+}
+
+// A stored PBKDF2-HMAC-SHA256 password hash and its parameters
+struct PasswordHash {
+    rounds: u32,
+    salt: String,
+    digest: [u8; 32],
+}
+
+// Users loaded from a static list, one per line:
+//   username:pbkdf2-sha256$<rounds>$<salt>$<hex digest>:US,CA:10
+// The country list and session limit are optional. Hashes are made with
+// --hash-password.
+pub struct StaticCredentialStore {
+    path: Option<String>,
+    users: RwLock<HashMap<String, UserEntry>>,
+    modified: RwLock<Option<SystemTime>>,
+    // Rounds of the stored hashes. Unknown usernames cost as much to check,
+    // so response times don't tell which users exist.
+    rounds: u32,
+}
+
+impl StaticCredentialStore {
+    pub fn from_file(path: &str, rounds: u32) -> std::io::Result<Self> {
+        let store = Self {
+            path: Some(path.to_string()),
+            users: RwLock::new(HashMap::new()),
+            modified: RwLock::new(None),
+            rounds,
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    // Entries are separated by ';' since ',' is used by the country list
+    pub fn from_env(value: &str, rounds: u32) -> Self {
+        let users = parse_entries(value.split(';'));
+        info!("Loaded {} users from environment", users.len());
+        Self {
+            path: None,
+            users: RwLock::new(users),
+            modified: RwLock::new(None),
+            rounds,
+        }
+    }
+
+    pub fn reload(&self) -> std::io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let modified = std::fs::metadata(path)?.modified().ok();
+        let content = std::fs::read_to_string(path)?;
+        let users = parse_entries(content.lines());
+        info!("Loaded {} users from {}", users.len(), path);
+
+        *self.users.write().unwrap() = users;
+        *self.modified.write().unwrap() = modified;
+        Ok(())
+    }
+
+    // Poll the users file and reload it whenever it changes on disk
+    pub fn spawn_reloader(self: &Arc<Self>) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let store = Arc::clone(self);
+
+        tokio::spawn(async move {
+            let mut ticker = interval(RELOAD_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                if modified.is_none() || modified == *store.modified.read().unwrap() {
+                    continue;
+                }
+                if let Err(e) = store.reload() {
+                    error!("Failed to reload users file {}: {}", path, e);
+                }
+            }
+        });
+    }
+}
+
+impl CredentialStore for StaticCredentialStore {
+    fn authenticate(&self, username: &str, password: &str) -> Option<User> {
+        let users = self.users.read().unwrap();
+        let Some(entry) = users.get(username) else {
+            hash_password(DUMMY_SALT, password, self.rounds);
+            debug!("Unknown user {}", username);
+            return None;
+        };
+
+        let hash = &entry.hash;
+        if constant_time_eq(&hash_password(&hash.salt, password, hash.rounds), &hash.digest) {
+            Some(entry.user.clone())
+        } else {
+            debug!("Invalid password for user {}", username);
+            None
+        }
+    }
+}
+
+fn parse_entries<'a>(lines: impl Iterator<Item = &'a str>) -> HashMap<String, UserEntry> {
+    let mut users = HashMap::new();
+
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split(':');
+        let username = fields.next().unwrap_or_default();
+        let hash = match fields.next().and_then(parse_hash) {
+            Some(parsed) => parsed,
+            None => {
+                warn!("Skipping user {} with malformed password hash", username);
+                continue;
+            }
+        };
+
+        let allowed_countries = fields
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let max_sessions = match fields.next().map(str::trim).filter(|s| !s.is_empty()) {
+            Some(limit) => match limit.parse::<usize>() {
+                Ok(limit) => Some(limit),
+                Err(_) => {
+                    warn!("Skipping user {} with invalid session limit", username);
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        users.insert(
+            username.to_string(),
+            UserEntry {
+                hash,
+                user: User {
+                    username: username.to_string(),
+                    allowed_countries,
+                    max_sessions,
+                },
+            },
+        );
+    }
+
+    users
+}
+
+// Parses "pbkdf2-sha256$<rounds>$<salt>$<hex digest>"
+fn parse_hash(field: &str) -> Option<PasswordHash> {
+    let mut parts = field.split('$');
+    if parts.next()? != "pbkdf2-sha256" {
+        return None;
+    }
+    let rounds = parts.next()?.parse().ok().filter(|rounds| *rounds > 0)?;
+    let salt = parts.next()?.to_string();
+    let hex = parts.next()?;
+    if salt.is_empty() || hex.len() != 64 || parts.next().is_some() {
+        return None;
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(PasswordHash { rounds, salt, digest })
+}
+
+pub fn hash_password(salt: &str, password: &str, rounds: u32) -> [u8; 32] {
+    let mut digest = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut digest);
+    digest
+}
+
+// Build the password field of a users file entry with a random salt
+pub fn format_password_hash(password: &str) -> String {
+    format_hash(password, PBKDF2_ROUNDS)
+}
+
+fn format_hash(password: &str, rounds: u32) -> String {
+    let salt: String = (0..16).map(|_| format!("{:02x}", rand::random::<u8>())).collect();
+    let hex: String = hash_password(&salt, password, rounds)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("pbkdf2-sha256${}${}${}", rounds, salt, hex)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Few rounds keep the tests fast, stores and hashes take them as a parameter
+    const TEST_ROUNDS: u32 = 1000;
+
+    #[test]
+    fn static_store_authenticates_hashed_users() {
+        let entry = format!("alice:{}:US, ca:2", format_hash("secret", TEST_ROUNDS));
+        let store = StaticCredentialStore::from_env(&format!("# comment;{};bob:plain", entry), TEST_ROUNDS);
+
+        let user = store.authenticate("alice", "secret").unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.allowed_countries, vec!["US", "ca"]);
+        assert_eq!(user.max_sessions, Some(2));
+
+        assert!(store.authenticate("alice", "wrong").is_none());
+        assert!(store.authenticate("bob", "plain").is_none());
+        assert!(store.authenticate("carol", "secret").is_none());
+    }
+
+    #[test]
+    fn optional_fields_default_to_unrestricted() {
+        let store = StaticCredentialStore::from_env(&format!("dave:{}", format_hash("pw", TEST_ROUNDS)), TEST_ROUNDS);
+        let user = store.authenticate("dave", "pw").unwrap();
+        assert!(user.allowed_countries.is_empty());
+        assert_eq!(user.max_sessions, None);
+    }
+
+    #[test]
+    fn password_hash_carries_its_parameters() {
+        let hash = parse_hash(&format_hash("pw", TEST_ROUNDS)).unwrap();
+        assert_eq!(hash.rounds, TEST_ROUNDS);
+        assert_eq!(hash.salt.len(), 32);
+        assert_eq!(hash.digest, hash_password(&hash.salt, "pw", TEST_ROUNDS));
+
+        // Plain SHA-256 entries and nonsensical parameters are refused
+        let digest = "0".repeat(64);
+        assert!(parse_hash(&format!("sha256$salt${}", digest)).is_none());
+        assert!(parse_hash(&format!("pbkdf2-sha256$0$salt${}", digest)).is_none());
+        assert!(parse_hash(&format!("pbkdf2-sha256$1000$${}", digest)).is_none());
+        assert!(parse_hash("pbkdf2-sha256$1000$salt$abc").is_none());
+        assert!(parse_hash(&format!("pbkdf2-sha256$1000$salt${}", "é".repeat(32))).is_none());
+    }
+}
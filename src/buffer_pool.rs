@@ -58,10 +58,7 @@ impl BufferPoolShard {
     async fn get_buffer(&self) -> BytesMut {
         let mut buffers = self.buffers.lock().await;
         if let Some(mut buffer) = buffers.pop_front() {
-            debug!(
-                "Acquired buffer from pool, buffers remaining: {}",
-                buffers.len()
-            );
+            debug!("Acquired buffer from pool, buffers remaining: {}", buffers.len());
             if buffer.capacity() < MAX_BUF_SIZE {
                 debug!("Buffer capacity is less than max, reallocating");
                 buffer = BytesMut::with_capacity(MAX_BUF_SIZE);
@@ -79,10 +76,7 @@ impl BufferPoolShard {
     async fn return_buffer(&self, buffer: BytesMut) {
         let mut buffers = self.buffers.lock().await;
         if buffers.len() < POOL_SIZE {
-            debug!(
-                "Returning buffer to pool, buffers now: {}",
-                buffers.len() + 1
-            );
+            debug!("Returning buffer to pool, buffers now: {}", buffers.len() + 1);
             buffers.push_back(buffer);
         } else {
             debug!("Buffer pool full, discarding buffer");
@@ -1,8 +1,18 @@
+use crate::auth::{format_password_hash, AuthPolicy};
+use crate::packet::{parse_max_frame_size, DEFAULT_MAX_FRAME_SIZE};
+use crate::routing::parse_ttl;
+use crate::version_policy::DEFAULT_SLAVE_VERSION_REQ;
+
 use dotenv::dotenv;
 use getopts::Options;
+use ipnet::IpNet;
 use log::error;
 use std::env;
+use std::io::IsTerminal;
 use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_STICKY_TTL: Duration = Duration::from_secs(600);
 
 pub struct Config {
     pub proxy_mode: u8,                      // 1 for sticky, 2 for non-sticky
@@ -10,8 +20,37 @@ pub struct Config {
     pub verbosity: String,                   // Verbosity level (trace, debug, info, warn, error)
     pub master_addr: String,                 // Master address for slave connections
     pub socks_addr: String,                  // Address for SOCKS5 client connections
+    pub http_addr: Option<String>,           // Address for HTTP proxy clients, disabled if unset
     pub metrics_addr: String,
+    pub users_file: Option<String>,              // Path to the proxy users file
+    pub users_env: Option<String>,               // ';'-separated user entries from PROXY_USERS
+    pub auth_policy: Option<AuthPolicy>,         // Accepted auth methods, defaults from whether users are configured
+    pub sticky_ttl: Duration,                    // Default lifetime of a sticky session
+    pub proxy_protocol: bool,                    // Expect PROXY protocol headers on the client listeners
+    pub proxy_protocol_trusted: Vec<IpNet>,      // Upstreams allowed to send PROXY protocol headers
+    pub max_frame_size: usize,                   // Largest frame payload accepted from a slave
+    pub slave_version_req: String,               // Semver requirement slave versions must satisfy
+    pub slave_version_deny: String,              // Comma-separated slave versions that are refused
+    pub slave_version_file: Option<String>,      // Reloadable policy file overriding the two above
+    pub slave_secret: Option<String>,            // Fleet-wide secret slaves authenticate with (env only)
+    pub slave_secrets_file: Option<String>,      // Per-slave secrets, "slave_id secret" per line
+    pub slave_tls_cert: Option<String>,          // PEM certificate chain, enables TLS on the slave listener
+    pub slave_tls_key: Option<String>,           // PEM private key for the certificate above
+    pub slave_tls_client_ca: Option<String>,     // CA slave client certificates must chain to
+    pub socks_tls_addr: Option<String>,          // Address for SOCKS clients over TLS, disabled if unset
+    pub https_addr: Option<String>,              // Address for HTTP proxy clients over TLS, disabled if unset
+    pub client_tls_certs: Vec<(String, String)>, // Certificate and key pairs for the TLS client listeners
+}
+// Prompt without echo on a terminal, otherwise take the first line of stdin
+fn read_password() -> std::io::Result<String> {
+    if std::io::stdin().is_terminal() {
+        return rpassword::prompt_password("Password: ");
+    }
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
 }
+
 pub fn parse_args() -> Config {
     // Load environment variables from .env file
     dotenv().ok();
@@ -44,7 +83,108 @@ pub fn parse_args() -> Config {
         "Comma-separated list of allowed countries for slaves",
         "LOCATIONS",
     );
+    opts.optopt(
+        "",
+        "http",
+        "The address on which to listen for HTTP proxy clients",
+        "HTTP_ADDRESS",
+    );
     opts.optopt("m", "metrics", "Set metrics server", "TRANSFER_ADDRESS");
+    opts.optopt(
+        "u",
+        "users",
+        "File of SOCKS5 users allowed to authenticate",
+        "USERS_FILE",
+    );
+    opts.optopt(
+        "",
+        "auth",
+        "Client authentication: required, none or optional",
+        "POLICY",
+    );
+    opts.optopt(
+        "",
+        "sticky-ttl",
+        "Default lifetime of a sticky session (e.g. 30s, 10m, 1h)",
+        "TTL",
+    );
+    opts.optflag(
+        "",
+        "proxy-protocol",
+        "Accept PROXY protocol v1/v2 headers from trusted upstreams",
+    );
+    opts.optopt(
+        "",
+        "proxy-protocol-trusted",
+        "Comma-separated CIDRs allowed to send PROXY protocol headers",
+        "CIDRS",
+    );
+    opts.optopt(
+        "",
+        "max-frame-size",
+        "Largest frame payload in bytes accepted from a slave, at least 65536",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "slave-versions",
+        "Semver requirement for slave versions (e.g. \">=1.0.9, <2\")",
+        "REQ",
+    );
+    opts.optopt(
+        "",
+        "slave-version-deny",
+        "Comma-separated slave versions to refuse",
+        "VERSIONS",
+    );
+    opts.optopt(
+        "",
+        "slave-version-file",
+        "Slave version policy file, reloaded when it changes",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "slave-secrets-file",
+        "Per-slave authentication secrets, one \"slave_id secret\" per line",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "slave-tls-cert",
+        "PEM certificate chain to serve TLS to slaves with",
+        "FILE",
+    );
+    opts.optopt("", "slave-tls-key", "PEM private key for --slave-tls-cert", "FILE");
+    opts.optopt(
+        "",
+        "slave-tls-client-ca",
+        "Require slave client certificates signed by this CA, their name is the slave ID",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "socks-tls",
+        "The address on which to listen for SOCKS clients over TLS",
+        "SOCKS_TLS_ADDRESS",
+    );
+    opts.optopt(
+        "",
+        "https",
+        "The address on which to listen for HTTP proxy clients over TLS",
+        "HTTPS_ADDRESS",
+    );
+    opts.optopt(
+        "",
+        "client-tls-certs",
+        "';'-separated \"cert,key\" PEM file pairs for the TLS client listeners, picked by SNI",
+        "PAIRS",
+    );
+    opts.optflag(
+        "",
+        "hash-password",
+        "Read a password from the terminal or stdin, print its users file hash and exit",
+    );
     opts.optopt(
         "v",
         "verbosity",
@@ -57,6 +197,20 @@ pub fn parse_args() -> Config {
         std::process::exit(-1);
     });
 
+    // Never from argv, where it would end up in the process list and shell history
+    if matches.opt_present("hash-password") {
+        match read_password() {
+            Ok(password) => {
+                println!("{}", format_password_hash(&password));
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("Failed to read password: {}", e);
+                std::process::exit(-1);
+            }
+        }
+    }
+
     // Parse proxy_mode (stick or nonstick)
     let proxy_mode: String = matches
         .opt_str("p")
@@ -102,17 +256,170 @@ pub fn parse_args() -> Config {
         .opt_str("s")
         .unwrap_or_else(|| env::var("SOCKS_ADDR").unwrap_or_else(|_| "0.0.0.0:1081".to_string()));
 
+    let http_addr = matches
+        .opt_str("http")
+        .or_else(|| env::var("HTTP_ADDR").ok())
+        .filter(|addr| !addr.is_empty());
+
     let metrics_addr = matches
         .opt_str("m")
         .unwrap_or_else(|| env::var("METRICS_ADDR").unwrap_or_else(|_| "0.0.0.0:9091".to_string()));
 
+    let users_file = matches
+        .opt_str("u")
+        .or_else(|| env::var("USERS_FILE").ok())
+        .filter(|path| !path.is_empty());
+
+    let users_env = env::var("PROXY_USERS").ok().filter(|users| !users.is_empty());
+
+    let auth_policy = matches
+        .opt_str("auth")
+        .or_else(|| env::var("AUTH_POLICY").ok())
+        .filter(|policy| !policy.is_empty())
+        .and_then(|policy| match policy.to_ascii_lowercase().as_str() {
+            "required" => Some(AuthPolicy::Required),
+            "none" => Some(AuthPolicy::NoAuth),
+            "optional" => Some(AuthPolicy::Optional),
+            _ => {
+                error!("Invalid auth policy {}. Using default.", policy);
+                None
+            }
+        });
+
+    let sticky_ttl = matches
+        .opt_str("sticky-ttl")
+        .or_else(|| env::var("STICKY_TTL").ok())
+        .map(|ttl| {
+            parse_ttl(&ttl).unwrap_or_else(|e| {
+                error!("{}. Using default ({}s).", e, DEFAULT_STICKY_TTL.as_secs());
+                DEFAULT_STICKY_TTL
+            })
+        })
+        .unwrap_or(DEFAULT_STICKY_TTL);
+
+    let proxy_protocol = matches.opt_present("proxy-protocol")
+        || env::var("PROXY_PROTOCOL").is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"));
+
+    let proxy_protocol_trusted: Vec<IpNet> = matches
+        .opt_str("proxy-protocol-trusted")
+        .unwrap_or_else(|| env::var("PROXY_PROTOCOL_TRUSTED").unwrap_or_default())
+        .split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .filter_map(|cidr| {
+            // Accept bare addresses as single-host networks
+            cidr.parse::<IpNet>()
+                .or_else(|_| cidr.parse::<std::net::IpAddr>().map(IpNet::from))
+                .map_err(|_| error!("Ignoring invalid trusted PROXY protocol CIDR: {}", cidr))
+                .ok()
+        })
+        .collect();
+
+    if proxy_protocol && proxy_protocol_trusted.is_empty() {
+        error!("PROXY protocol is enabled but no trusted upstreams are configured.");
+    }
+
+    let max_frame_size = matches
+        .opt_str("max-frame-size")
+        .or_else(|| env::var("MAX_FRAME_SIZE").ok())
+        .map(|size| {
+            parse_max_frame_size(&size).unwrap_or_else(|e| {
+                error!("{}. Using default ({}).", e, DEFAULT_MAX_FRAME_SIZE);
+                DEFAULT_MAX_FRAME_SIZE
+            })
+        })
+        .unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+
+    let slave_version_req = matches
+        .opt_str("slave-versions")
+        .or_else(|| env::var("SLAVE_VERSION_REQ").ok())
+        .filter(|req| !req.is_empty())
+        .unwrap_or_else(|| DEFAULT_SLAVE_VERSION_REQ.to_string());
+
+    let slave_version_deny = matches
+        .opt_str("slave-version-deny")
+        .unwrap_or_else(|| env::var("SLAVE_VERSION_DENY").unwrap_or_default());
+
+    let slave_version_file = matches
+        .opt_str("slave-version-file")
+        .or_else(|| env::var("SLAVE_VERSION_FILE").ok())
+        .filter(|path| !path.is_empty());
+
+    // Not an option so the secret doesn't show up in the process list
+    let slave_secret = env::var("SLAVE_SECRET").ok().filter(|secret| !secret.is_empty());
+
+    let slave_secrets_file = matches
+        .opt_str("slave-secrets-file")
+        .or_else(|| env::var("SLAVE_SECRETS_FILE").ok())
+        .filter(|path| !path.is_empty());
+
+    let slave_tls_cert = matches
+        .opt_str("slave-tls-cert")
+        .or_else(|| env::var("SLAVE_TLS_CERT").ok())
+        .filter(|path| !path.is_empty());
+
+    let slave_tls_key = matches
+        .opt_str("slave-tls-key")
+        .or_else(|| env::var("SLAVE_TLS_KEY").ok())
+        .filter(|path| !path.is_empty());
+
+    let slave_tls_client_ca = matches
+        .opt_str("slave-tls-client-ca")
+        .or_else(|| env::var("SLAVE_TLS_CLIENT_CA").ok())
+        .filter(|path| !path.is_empty());
+
+    let socks_tls_addr = matches
+        .opt_str("socks-tls")
+        .or_else(|| env::var("SOCKS_TLS_ADDR").ok())
+        .filter(|addr| !addr.is_empty());
+
+    let https_addr = matches
+        .opt_str("https")
+        .or_else(|| env::var("HTTPS_ADDR").ok())
+        .filter(|addr| !addr.is_empty());
+
+    // The first pair is served to clients that send no SNI name we know
+    let client_tls_certs = matches
+        .opt_str("client-tls-certs")
+        .unwrap_or_else(|| env::var("CLIENT_TLS_CERTS").unwrap_or_default())
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| match pair.split_once(',') {
+            Some((cert, key)) => Some((cert.trim().to_string(), key.trim().to_string())),
+            None => {
+                error!("Ignoring client TLS entry without a key: {}", pair);
+                None
+            }
+        })
+        .collect();
+
     Config {
         proxy_mode: client_assign_mode,
         allowed_locations,
         verbosity,
         master_addr,
         socks_addr,
+        http_addr,
         metrics_addr,
+        users_file,
+        users_env,
+        auth_policy,
+        sticky_ttl,
+        proxy_protocol,
+        proxy_protocol_trusted,
+        max_frame_size,
+        slave_version_req,
+        slave_version_deny,
+        slave_version_file,
+        slave_secret,
+        slave_secrets_file,
+        slave_tls_cert,
+        slave_tls_key,
+        slave_tls_client_ca,
+        socks_tls_addr,
+        https_addr,
+        client_tls_certs,
     }
 }
 
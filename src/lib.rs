@@ -0,0 +1,18 @@
+pub mod auth;
+pub mod buffer_pool;
+pub mod conf;
+pub mod http;
+pub mod load_balancing;
+pub mod logger;
+pub mod metrics;
+pub mod packet;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod routing;
+pub mod server;
+pub mod slave_auth;
+pub mod socks4;
+pub mod socks5;
+pub mod tls;
+pub mod utils;
+pub mod version_policy;
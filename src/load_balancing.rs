@@ -1,9 +1,5 @@
-use bytes::BytesMut;
-use log::debug;
-use std::collections::VecDeque;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
-use tokio::sync::Mutex as AsyncMutex;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Token(pub u32);
@@ -66,8 +62,9 @@ impl Balance for IpHash {
         for (token, weight) in tokens.iter().zip(weights.iter()) {
             let weight = *weight as usize * ratio as usize;
 
+            // Virtual nodes are keyed by token so equal weights do not collide
             for vidx in 0..=weight {
-                let buf = format!("{0} 114514", vidx);
+                let buf = format!("{0} {1} 114514", token, vidx);
                 let hash = chash(buf.as_bytes());
                 nodes.push(Node {
                     hash,
@@ -98,9 +95,28 @@ impl Balance for IpHash {
             IpAddr::V6(x) => chash_for_ip(&x.octets()),
         };
 
+        self.lookup(hash)
+    }
+}
+
+impl IpHash {
+    // Hash an arbitrary key, such as a session name, onto the ring
+    pub fn next_by_key(&self, key: &[u8]) -> Option<Token> {
+        if self.total == 0 {
+            return None;
+        }
+
+        if self.total == 1 {
+            return self.nodes.first().map(|node| node.token);
+        }
+
+        self.lookup(chash(key))
+    }
+
+    fn lookup(&self, hash: u32) -> Option<Token> {
         let idx = match self.nodes.binary_search_by_key(&hash, |node| node.hash) {
             Ok(idx) => idx,
-            Err(idx) if idx >= self.nodes.len() as usize => 0,
+            Err(idx) if idx >= self.nodes.len() => 0,
             Err(idx) => idx,
         };
 
@@ -181,10 +197,7 @@ impl Balance for RoundRobin {
                 node.ew += 1; // Slowly restore the effective weight
             }
 
-            if best
-                .as_ref()
-                .map_or(true, |best_node| node.cw > best_node.cw)
-            {
+            if best.as_ref().is_none_or(|best_node| node.cw > best_node.cw) {
                 best = Some(node);
             }
         }
@@ -205,7 +218,10 @@ pub enum Strategy {
 }
 
 pub struct BalanceCtx<'a> {
-    pub src_ip: &'a IpAddr,
+    // Client peer address, or the one recovered from a PROXY protocol header
+    pub src_addr: &'a SocketAddr,
+    // Hashed instead of the source IP when the client names a session
+    pub session_key: Option<&'a str>,
 }
 
 #[derive(Debug, Clone)]
@@ -218,15 +234,16 @@ impl Balancer {
     pub fn new(strategy: Strategy, weights: &[u32], tokens: &[u32]) -> Self {
         match strategy {
             Strategy::IpHash => Balancer::IpHash(Arc::new(IpHash::new(weights, tokens))),
-            Strategy::RoundRobin => {
-                Balancer::RoundRobin(Arc::new(RoundRobin::new(weights, tokens)))
-            }
+            Strategy::RoundRobin => Balancer::RoundRobin(Arc::new(RoundRobin::new(weights, tokens))),
         }
     }
 
     pub fn next(&self, ctx: BalanceCtx) -> Option<Token> {
         match self {
-            Balancer::IpHash(balancer) => balancer.next(ctx.src_ip),
+            Balancer::IpHash(balancer) => match ctx.session_key {
+                Some(key) => balancer.next_by_key(key.as_bytes()),
+                None => balancer.next(&ctx.src_addr.ip()),
+            },
             Balancer::RoundRobin(balancer) => balancer.next(&()),
         }
     }
@@ -243,12 +260,7 @@ mod chash {
         let mut len = buf.len();
 
         while len >= 4 {
-            h = h.wrapping_add(
-                (b[0] as u32)
-                    | ((b[1] as u32) << 8)
-                    | ((b[2] as u32) << 16)
-                    | ((b[3] as u32) << 24),
-            );
+            h = h.wrapping_add((b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24));
 
             h = h.wrapping_mul(M);
             h ^= h >> 16;
@@ -388,7 +400,7 @@ mod tests {
         let ip3 = "114.51.4.19".parse::<IpAddr>().unwrap();
         let ip4 = "2001:4860:4860::8888".parse::<IpAddr>().unwrap();
 
-        let iphash = IpHash::new(&vec![1, 2, 3, 4], &vec![0, 1, 2, 3]);
+        let iphash = IpHash::new(&[1, 2, 3, 4], &[0, 1, 2, 3]);
         assert_eq!(iphash.total, 4);
         assert!(iphash.nodes.len() >= (1 + 2 + 3 + 4) * 128 / 4);
 
@@ -416,17 +428,13 @@ mod tests {
     #[test]
     fn ih_same_weight() {
         let tokens: Vec<u32> = (0..=15).collect();
-        let iphash = IpHash::new(&vec![1; 16], &tokens);
+        let iphash = IpHash::new(&[1; 16], &tokens);
         let mut distro = [0f64; 16];
 
         let mut total: usize = 0;
-        for ip in (0..=u32::MAX)
-            .map(Ipv4Addr::from)
-            .map(IpAddr::from)
-            .step_by(127)
-        {
+        for ip in (0..=u32::MAX).map(Ipv4Addr::from).map(IpAddr::from).step_by(127) {
             let token = iphash.next(&ip).unwrap();
-            distro[token.0 as usize] += 1 as f64;
+            distro[token.0 as usize] += 1.0;
             total += 1;
         }
 
@@ -454,13 +462,9 @@ mod tests {
         let mut distro = [0f64; 16];
 
         let mut total: usize = 0;
-        for ip in (0..=u32::MAX)
-            .map(Ipv4Addr::from)
-            .map(IpAddr::from)
-            .step_by(127)
-        {
+        for ip in (0..=u32::MAX).map(Ipv4Addr::from).map(IpAddr::from).step_by(127) {
             let token = iphash.next(&ip).unwrap();
-            distro[token.0 as usize] += 1 as f64;
+            distro[token.0 as usize] += 1.0;
             total += 1;
         }
 
@@ -491,7 +495,7 @@ mod tests {
     #[test]
     fn rr_same_weight() {
         let tokens: Vec<u32> = (0..=254).collect();
-        let rr = RoundRobin::new(&vec![1; 255], &tokens); // 255 nodes with weight 1
+        let rr = RoundRobin::new(&[1; 255], &tokens); // 255 nodes with weight 1
         let mut distro = [0f64; 255];
 
         for _ in 0..1_000_000 {
@@ -548,70 +552,3 @@ mod tests {
         println!("mean diff: {}", mean_diff.mean());
     }
 }
-
-pub const MAX_BUF_SIZE: usize = 8192;
-pub const POOL_SIZE: usize = 200;
-
-// Sharded Buffer Pool for high concurrency
-#[derive(Clone)]
-pub struct ShardedBufferPool {
-    shards: Vec<Arc<BufferPoolShard>>,
-}
-
-impl ShardedBufferPool {
-    pub fn new(num_shards: usize, pool_size: usize) -> Self {
-        let shards = (0..num_shards)
-            .map(|_| Arc::new(BufferPoolShard::new(pool_size)))
-            .collect();
-        Self { shards }
-    }
-
-    pub async fn get_buffer(&self, id: usize) -> BytesMut {
-        let shard = &self.shards[id % self.shards.len()];
-        shard.get_buffer().await
-    }
-
-    pub async fn return_buffer(&self, id: usize, buffer: BytesMut) {
-        let shard = &self.shards[id % self.shards.len()];
-        shard.return_buffer(buffer).await;
-    }
-}
-
-struct BufferPoolShard {
-    buffers: AsyncMutex<VecDeque<BytesMut>>,
-}
-
-impl BufferPoolShard {
-    fn new(pool_size: usize) -> Self {
-        let mut buffers = VecDeque::with_capacity(pool_size);
-        for _ in 0..pool_size {
-            buffers.push_back(BytesMut::with_capacity(MAX_BUF_SIZE));
-        }
-        Self {
-            buffers: AsyncMutex::new(buffers),
-        }
-    }
-
-    async fn get_buffer(&self) -> BytesMut {
-        let mut buffers = self.buffers.lock().await;
-        if let Some(mut buffer) = buffers.pop_front() {
-            if buffer.capacity() < MAX_BUF_SIZE {
-                buffer = BytesMut::with_capacity(MAX_BUF_SIZE);
-            } else {
-                buffer.clear(); // Prepare buffer for reuse
-            }
-            buffer
-        } else {
-            BytesMut::with_capacity(MAX_BUF_SIZE)
-        }
-    }
-
-    async fn return_buffer(&self, buffer: BytesMut) {
-        let mut buffers = self.buffers.lock().await;
-        if buffers.len() < POOL_SIZE {
-            buffers.push_back(buffer);
-        } else {
-            debug!("Buffer pool full, discarding buffer");
-        }
-    }
-}
@@ -1,25 +1,18 @@
-mod conf;
-mod logger;
-mod server;
-mod proxy;
-mod buffer_pool;
-mod metrics;
-mod utils;
-mod packet;
-mod load_balancing;
-mod socks5;
-
-use conf::parse_args;
-use logger::init_logging;
-use server::{start_slave_listener, start_client_listener};
-use std::sync::Arc;
+use log::{info, warn};
+use net_relay::auth::{AuthPolicy, ClientAuth, CredentialStore, StaticCredentialStore, PBKDF2_ROUNDS};
+use net_relay::buffer_pool::ShardedBufferPool;
+use net_relay::conf::parse_args;
+use net_relay::logger::init_logging;
+use net_relay::metrics::{start_metrics_server, Metrics};
+use net_relay::proxy::{run_affinity_sweeper, Frontend, ProxyManager};
+use net_relay::proxy_protocol::ProxyProtocolConfig;
+use net_relay::server::{start_client_listener, start_slave_listener};
+use net_relay::slave_auth::SlaveAuth;
+use net_relay::tls;
+use net_relay::version_policy::{VersionPolicy, VersionPolicyStore};
 use prometheus::Registry;
+use std::sync::Arc;
 use tokio::sync::Semaphore;
-use tokio::sync::Mutex as AsyncMutex;
-use log::info;
-use crate::metrics::{start_metrics_server, Metrics};
-use crate::proxy::ProxyManager;
-use crate::buffer_pool::ShardedBufferPool;
 
 #[cfg(all(not(target_os = "windows"), feature = "jemalloc"))]
 #[global_allocator]
@@ -35,7 +28,7 @@ const NUM_SHARDS: usize = 8;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let config = parse_args(); 
+    let config = parse_args();
 
     init_logging(&config.verbosity);
 
@@ -47,10 +40,80 @@ async fn main() -> std::io::Result<()> {
     tokio::spawn(start_metrics_server(Arc::new(registry)));
 
     // Proxy manager and buffer pool
-    let proxy_manager = Arc::new(AsyncMutex::new(ProxyManager::new(config.proxy_mode)));
+    let proxy_manager = Arc::new(ProxyManager::new(config.proxy_mode, config.sticky_ttl));
     let slave_buffer_pool = Arc::new(ShardedBufferPool::new(NUM_SHARDS, POOL_SIZE));
     let client_buffer_pool = Arc::new(ShardedBufferPool::new(NUM_SHARDS, POOL_SIZE));
 
+    tokio::spawn(run_affinity_sweeper(Arc::clone(&proxy_manager), Arc::clone(&metrics)));
+
+    // Credential backend for client username/password authentication
+    let credentials: Option<Arc<dyn CredentialStore>> = if let Some(path) = &config.users_file {
+        let store = Arc::new(StaticCredentialStore::from_file(path, PBKDF2_ROUNDS)?);
+        store.spawn_reloader();
+        Some(store)
+    } else {
+        config
+            .users_env
+            .as_deref()
+            .map(|users| Arc::new(StaticCredentialStore::from_env(users, PBKDF2_ROUNDS)) as Arc<dyn CredentialStore>)
+    };
+
+    // Requiring credentials with nothing to check them against would let
+    // any username and password in
+    if config.auth_policy == Some(AuthPolicy::Required) && credentials.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Auth policy \"required\" needs a users file or PROXY_USERS",
+        ));
+    }
+
+    // Configured users have to authenticate unless told otherwise
+    let policy = config.auth_policy.unwrap_or(if credentials.is_some() {
+        AuthPolicy::Required
+    } else {
+        AuthPolicy::Optional
+    });
+    let auth = Arc::new(ClientAuth::new(policy, credentials));
+
+    // Slave versions allowed to register
+    let version_policy = match &config.slave_version_file {
+        Some(path) => {
+            let store = Arc::new(VersionPolicyStore::from_file(path)?);
+            store.spawn_reloader();
+            store
+        }
+        None => {
+            let policy = VersionPolicy::parse(&config.slave_version_req, &config.slave_version_deny)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            Arc::new(VersionPolicyStore::new(policy))
+        }
+    };
+
+    // Secrets slaves authenticate with, registration is open without any
+    let slave_auth = Arc::new(match &config.slave_secrets_file {
+        Some(path) => SlaveAuth::from_file(path, config.slave_secret.clone())?,
+        None => SlaveAuth::new(config.slave_secret.clone(), Default::default()),
+    });
+    if !slave_auth.is_enabled() {
+        warn!(
+            "No slave secrets configured, any host reaching {} can register as a slave",
+            config.master_addr
+        );
+    }
+    slave_auth.spawn_sweeper();
+
+    // TLS on the slave listener needs both halves of the key pair
+    let slave_tls = match (&config.slave_tls_cert, &config.slave_tls_key) {
+        (Some(cert), Some(key)) => Some(tls::slave_acceptor(cert, key, config.slave_tls_client_ca.as_deref())?),
+        (None, None) if config.slave_tls_client_ca.is_none() => None,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Slave TLS needs both a certificate and a key",
+            ))
+        }
+    };
+
     // Start Slave listener and Client listener
     info!("Waiting for Slave nodes on {}", config.master_addr);
     start_slave_listener(
@@ -58,16 +121,75 @@ async fn main() -> std::io::Result<()> {
         Arc::clone(&proxy_manager),
         Arc::clone(&slave_buffer_pool),
         Arc::clone(&metrics),
-        Arc::clone(&config.allowed_locations)
-    ).await;
+        Arc::clone(&config.allowed_locations),
+        version_policy,
+        slave_auth,
+        slave_tls,
+        config.max_frame_size,
+    )
+    .await;
+
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+    let proxy_protocol = config.proxy_protocol.then(|| {
+        Arc::new(ProxyProtocolConfig {
+            trusted: config.proxy_protocol_trusted.clone(),
+        })
+    });
+
+    // TLS client listeners pick their certificate by the SNI name clients send
+    let client_tls = match (&config.socks_tls_addr, &config.https_addr) {
+        (None, None) => None,
+        _ => Some(tls::client_acceptor(&config.client_tls_certs)?),
+    };
+
+    // Optional front-ends sharing the same slaves and users
+    let frontends = [
+        (config.http_addr.clone(), Frontend::Http, None, "HTTP proxy"),
+        (
+            config.socks_tls_addr.clone(),
+            Frontend::Socks,
+            client_tls.clone(),
+            "SOCKS over TLS",
+        ),
+        (config.https_addr.clone(), Frontend::Http, client_tls, "HTTPS proxy"),
+    ];
+    for (addr, frontend, tls, name) in frontends {
+        let Some(addr) = addr else { continue };
+        log::info!("Waiting for {} clients on {}", name, addr);
+        tokio::spawn({
+            let proxy_manager = Arc::clone(&proxy_manager);
+            let semaphore = Arc::clone(&semaphore);
+            let client_buffer_pool = Arc::clone(&client_buffer_pool);
+            let auth = Arc::clone(&auth);
+            let proxy_protocol = proxy_protocol.clone();
+            async move {
+                start_client_listener(
+                    &addr,
+                    frontend,
+                    proxy_manager,
+                    semaphore,
+                    client_buffer_pool,
+                    auth,
+                    proxy_protocol,
+                    tls,
+                )
+                .await;
+            }
+        });
+    }
 
     log::info!("Waiting for SOCKS5 clients on {}", config.socks_addr);
     start_client_listener(
         &config.socks_addr,
+        Frontend::Socks,
         Arc::clone(&proxy_manager),
-        Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS)),
-        Arc::clone(&client_buffer_pool)
-    ).await;
+        semaphore,
+        Arc::clone(&client_buffer_pool),
+        auth,
+        proxy_protocol,
+        None,
+    )
+    .await;
 
     Ok(())
 }
@@ -5,7 +5,7 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Response};
 use prometheus::Encoder;
 use prometheus::TextEncoder;
-use prometheus::{Counter, IntGauge, Registry};
+use prometheus::{Counter, IntGauge, IntGaugeVec, Opts, Registry};
 use std::net::{IpAddr, SocketAddrV4};
 use std::str::FromStr;
 use std::{error::Error, sync::Arc};
@@ -15,6 +15,11 @@ pub struct Metrics {
     pub slave_active_connections: IntGauge,
     pub slave_total_connections: Counter,
     pub slave_disconnections: Counter,
+    pub slaves_connected: IntGauge,
+    pub sticky_sessions: IntGauge,
+    pub sticky_session_ttl_min_seconds: IntGauge,
+    pub sticky_session_ttl_max_seconds: IntGauge,
+    pub slaves_by_version: IntGaugeVec,
 }
 
 impl Metrics {
@@ -26,15 +31,38 @@ impl Metrics {
             )
             .unwrap(),
 
-            slave_total_connections: Counter::new(
-                "slave_total_connections",
-                "Total number of slave connections made",
+            slave_total_connections: Counter::new("slave_total_connections", "Total number of slave connections made")
+                .unwrap(),
+
+            slave_disconnections: Counter::new("slave_disconnections", "Total number of slave disconnections").unwrap(),
+
+            slaves_connected: IntGauge::new(
+                "slaves_connected",
+                "Current number of connected slaves, each counted once however many connections it has",
+            )
+            .unwrap(),
+
+            sticky_sessions: IntGauge::new("sticky_sessions", "Current number of sticky sessions pinned to a slave")
+                .unwrap(),
+
+            sticky_session_ttl_min_seconds: IntGauge::new(
+                "sticky_session_ttl_min_seconds",
+                "Shortest remaining lifetime among the sticky sessions",
+            )
+            .unwrap(),
+
+            sticky_session_ttl_max_seconds: IntGauge::new(
+                "sticky_session_ttl_max_seconds",
+                "Longest remaining lifetime among the sticky sessions",
             )
             .unwrap(),
 
-            slave_disconnections: Counter::new(
-                "slave_disconnections",
-                "Total number of slave disconnections",
+            slaves_by_version: IntGaugeVec::new(
+                Opts::new(
+                    "slaves_by_version",
+                    "Current number of connected slaves per slave version, each counted once",
+                ),
+                &["version"],
             )
             .unwrap(),
         }
@@ -47,15 +75,26 @@ impl Metrics {
         registry
             .register(Box::new(self.slave_total_connections.clone()))
             .unwrap();
+        registry.register(Box::new(self.slave_disconnections.clone())).unwrap();
+        registry.register(Box::new(self.slaves_connected.clone())).unwrap();
+        registry.register(Box::new(self.sticky_sessions.clone())).unwrap();
+        registry
+            .register(Box::new(self.sticky_session_ttl_min_seconds.clone()))
+            .unwrap();
         registry
-            .register(Box::new(self.slave_disconnections.clone()))
+            .register(Box::new(self.sticky_session_ttl_max_seconds.clone()))
             .unwrap();
+        registry.register(Box::new(self.slaves_by_version.clone())).unwrap();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub async fn start_metrics_server(
-    registry: Arc<Registry>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+pub async fn start_metrics_server(registry: Arc<Registry>) -> Result<(), Box<dyn Error + Send + Sync>> {
     let make_svc = make_service_fn(move |_| {
         let registry = registry.clone();
         async move {
@@ -125,13 +164,11 @@ pub async fn start_metrics_server(
     if addr_parts.len() == 2 {
         let ip_str = addr_parts[0];
         let port_str = addr_parts[1];
-        if let Ok(ip_addr) = IpAddr::from_str(ip_str) {
-            if let IpAddr::V4(ipv4_addr) = ip_addr {
-                let port: u16 = port_str.parse().expect("Invalid port number");
-                let socket_addr = SocketAddrV4::new(ipv4_addr, port);
-                println!("Socket Address: {:?}", socket_addr);
-                addr = (ipv4_addr.octets(), port).into();
-            }
+        if let Ok(IpAddr::V4(ipv4_addr)) = IpAddr::from_str(ip_str) {
+            let port: u16 = port_str.parse().expect("Invalid port number");
+            let socket_addr = SocketAddrV4::new(ipv4_addr, port);
+            println!("Socket Address: {:?}", socket_addr);
+            addr = (ipv4_addr.octets(), port).into();
         }
     }
 
@@ -1,16 +1,19 @@
-use crate::proxy::{ProxyManager, Slave};
-use crate::utils::bytes_to_u32;
+use crate::proxy::{ClientEvent, ProxyManager, Slave};
+use crate::utils::{bytes_to_u32, get_socket_addr};
 
-use bytes::{BufMut, Bytes, BytesMut};
-use log::{debug, error};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::debug;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex as AsyncMutex;
 use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PacketType {
     Data = 0x00,
     Command = 0x01,
+    // UDP payload of an associate session, prefixed with ATYP + address + port
+    Datagram = 0x02,
 }
 
 impl PacketType {
@@ -18,18 +21,27 @@ impl PacketType {
         match value {
             0x00 => Some(PacketType::Data),
             0x01 => Some(PacketType::Command),
+            0x02 => Some(PacketType::Datagram),
             _ => None,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CommandType {
     SpeedCheck = 0x01,
     VersionCheck = 0x02,
     Heartbeat = 0x03,
     LocationCheck = 0x04,
     InitSession = 0x05,
+    CloseSession = 0x06,
+    HalfClose = 0x07,
+    UdpAssociate = 0x08,
+    Bind = 0x09,
+    Hello = 0x0A,
+    WindowUpdate = 0x0B,
+    ResumeSession = 0x0C,
+    Auth = 0x0D,
 }
 
 impl CommandType {
@@ -40,11 +52,289 @@ impl CommandType {
             0x03 => Some(CommandType::Heartbeat),
             0x04 => Some(CommandType::LocationCheck),
             0x05 => Some(CommandType::InitSession),
+            0x06 => Some(CommandType::CloseSession),
+            0x07 => Some(CommandType::HalfClose),
+            0x08 => Some(CommandType::UdpAssociate),
+            0x09 => Some(CommandType::Bind),
+            0x0A => Some(CommandType::Hello),
+            0x0B => Some(CommandType::WindowUpdate),
+            0x0C => Some(CommandType::ResumeSession),
+            0x0D => Some(CommandType::Auth),
             _ => None,
         }
     }
 }
 
+// Outcome of a slave's connect attempt, carried in its InitSession reply
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionStatus {
+    Success = 0x00,
+    GeneralFailure = 0x01,
+    Refused = 0x02,
+    HostUnreachable = 0x03,
+    NetworkUnreachable = 0x04,
+    TtlExpired = 0x05,
+    NotAllowed = 0x06,
+}
+
+impl SessionStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0x00 => SessionStatus::Success,
+            0x02 => SessionStatus::Refused,
+            0x03 => SessionStatus::HostUnreachable,
+            0x04 => SessionStatus::NetworkUnreachable,
+            0x05 => SessionStatus::TtlExpired,
+            0x06 => SessionStatus::NotAllowed,
+            _ => SessionStatus::GeneralFailure,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SessionAck {
+    pub status: SessionStatus,
+    // Local address the slave bound for the upstream connection, if reported
+    pub bound_addr: Option<SocketAddr>,
+}
+
+// Ack payload: status (1 byte), then optionally ATYP + address + port
+pub fn parse_session_ack(payload: &[u8]) -> Option<SessionAck> {
+    let status = SessionStatus::from_u8(*payload.first()?);
+    let bound_addr = get_socket_addr(&payload[1..]).map(|(addr, _)| addr);
+    Some(SessionAck { status, bound_addr })
+}
+
+// Protocol v1 is the original, hello-less exchange; v2 starts with a Hello
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+// Optional features a slave may support, negotiated per slave
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const UDP: Capabilities = Capabilities(1 << 0);
+    pub const BIND: Capabilities = Capabilities(1 << 1);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 2);
+    pub const FLOW_CONTROL: Capabilities = Capabilities(1 << 3);
+    // CloseSession and HalfClose frames
+    pub const CLOSE_FRAMES: Capabilities = Capabilities(1 << 4);
+    // Sessions survive a reconnect, see ResumeSession
+    pub const RESUME: Capabilities = Capabilities(1 << 5);
+    // InitSession is answered with a SessionAck once the target is reached
+    pub const SESSION_ACK: Capabilities = Capabilities(1 << 6);
+
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // Names of the known capabilities set, for logging
+    pub fn names(self) -> Vec<&'static str> {
+        [
+            (Capabilities::UDP, "udp"),
+            (Capabilities::BIND, "bind"),
+            (Capabilities::COMPRESSION, "compression"),
+            (Capabilities::FLOW_CONTROL, "flow-control"),
+            (Capabilities::CLOSE_FRAMES, "close-frames"),
+            (Capabilities::RESUME, "resume"),
+            (Capabilities::SESSION_ACK, "session-ack"),
+        ]
+        .into_iter()
+        .filter(|(capability, _)| self.contains(*capability))
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+// Hello payload: min version (2) + max version (2) + capability bits (4),
+// optionally followed by the slave's ID (UTF-8, rest of the payload)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub capabilities: Capabilities,
+    // Connections sent with the same ID are grouped into one logical slave
+    pub slave_id: Option<String>,
+}
+
+// What this master speaks. Compression is defined but not implemented.
+pub const MASTER_HELLO: Hello = Hello {
+    min_version: LEGACY_PROTOCOL_VERSION,
+    max_version: 2,
+    capabilities: Capabilities::UDP
+        .union(Capabilities::BIND)
+        .union(Capabilities::FLOW_CONTROL)
+        .union(Capabilities::CLOSE_FRAMES)
+        .union(Capabilities::RESUME)
+        .union(Capabilities::SESSION_ACK),
+    slave_id: None,
+};
+
+// With flow control each side may send this many data bytes per session
+// before it has to wait for the peer's WindowUpdate frames. WindowUpdate
+// carries the number of bytes consumed (u32) and adds that much credit.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+impl Hello {
+    // Highest version both sides speak and the features both support
+    pub fn negotiate(&self, peer: &Hello) -> Option<(u16, Capabilities)> {
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            return None;
+        }
+        Some((version, self.capabilities.intersection(peer.capabilities)))
+    }
+}
+
+pub fn parse_hello(payload: &[u8]) -> Option<Hello> {
+    let (fixed, id) = payload.split_at_checked(8)?;
+    let slave_id = match id.is_empty() {
+        true => None,
+        false => Some(String::from_utf8(id.to_vec()).ok()?),
+    };
+    Some(Hello {
+        min_version: u16::from_be_bytes([fixed[0], fixed[1]]),
+        max_version: u16::from_be_bytes([fixed[2], fixed[3]]),
+        capabilities: Capabilities(u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]])),
+        slave_id,
+    })
+}
+
+// Frame header: type (1) + session id (4) + command (1) + payload length (4)
+pub const HEADER_LEN: usize = 10;
+// Default cap on a frame's payload, well above any data or datagram frame
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+// Smallest cap allowed, leaving room for handshake frames and a full datagram
+pub const MIN_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+// Frame size cap in bytes, at least MIN_MAX_FRAME_SIZE
+pub fn parse_max_frame_size(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(size) if size >= MIN_MAX_FRAME_SIZE => Ok(size),
+        Ok(_) => Err(format!(
+            "Max frame size {} is below the minimum of {}",
+            value, MIN_MAX_FRAME_SIZE
+        )),
+        Err(_) => Err(format!("Invalid max frame size {}", value)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub packet_type: PacketType,
+    pub session_id: u32,
+    // Only set on command frames, the byte is zero otherwise
+    pub command_type: Option<CommandType>,
+    pub payload: Bytes,
+}
+
+// Protocol violations that end the connection with a slave
+#[derive(Debug)]
+pub enum FrameError {
+    UnknownPacketType(u8),
+    UnknownCommand(u8),
+    FrameTooLarge(usize),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::UnknownPacketType(value) => write!(f, "unknown packet type {:#04x}", value),
+            FrameError::UnknownCommand(value) => write!(f, "unknown command {:#04x}", value),
+            FrameError::FrameTooLarge(len) => write!(f, "frame payload of {} bytes exceeds the limit", len),
+            FrameError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+// Codec for the master <-> slave wire format
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    fn put_frame(frame: &Frame, dst: &mut BytesMut) {
+        dst.reserve(HEADER_LEN + frame.payload.len());
+        dst.put_u8(frame.packet_type as u8);
+        dst.put_u32(frame.session_id);
+        dst.put_u8(frame.command_type.map_or(0x00, |cmd| cmd as u8));
+        dst.put_u32(frame.payload.len() as u32);
+        dst.put_slice(&frame.payload);
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        // Validate the header before waiting on the payload, so a bogus
+        // length or type is rejected right away
+        let packet_type = PacketType::from_u8(src[0]).ok_or(FrameError::UnknownPacketType(src[0]))?;
+        let command_type = match packet_type {
+            PacketType::Command => Some(CommandType::from_u8(src[5]).ok_or(FrameError::UnknownCommand(src[5]))?),
+            PacketType::Data | PacketType::Datagram => None,
+        };
+        let payload_len = u32::from_be_bytes([src[6], src[7], src[8], src[9]]) as usize;
+        if payload_len > self.max_frame_size {
+            return Err(FrameError::FrameTooLarge(payload_len));
+        }
+
+        if src.len() < HEADER_LEN + payload_len {
+            src.reserve(HEADER_LEN + payload_len - src.len());
+            return Ok(None);
+        }
+
+        let session_id = bytes_to_u32(&src[1..5]);
+        src.advance(HEADER_LEN);
+        let payload = src.split_to(payload_len).freeze();
+        Ok(Some(Frame {
+            packet_type,
+            session_id,
+            command_type,
+            payload,
+        }))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), FrameError> {
+        if frame.payload.len() > self.max_frame_size {
+            return Err(FrameError::FrameTooLarge(frame.payload.len()));
+        }
+        Self::put_frame(&frame, dst);
+        Ok(())
+    }
+}
+
 fn build_command_frame(
     packet_type: PacketType,
     session_id: u32,
@@ -59,29 +349,46 @@ fn build_command_frame(
         payload.len()
     );
 
-    let mut frame = BytesMut::with_capacity(10 + payload.len());
-    frame.put_u8(packet_type as u8);
-    frame.put_u32(session_id);
+    let frame = Frame {
+        packet_type,
+        session_id,
+        command_type,
+        payload: Bytes::copy_from_slice(payload),
+    };
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    FrameCodec::put_frame(&frame, &mut buf);
+    buf.freeze()
+}
 
-    if let Some(cmd) = command_type {
-        frame.put_u8(cmd as u8);
-    } else {
-        frame.put_u8(0x00);
+pub fn build_speed_test_command(url: &str) -> Bytes {
+    debug!("Building speed test command for URL: {}", url);
+    build_command_frame(PacketType::Command, 0, Some(CommandType::SpeedCheck), url.as_bytes())
+}
+
+pub fn build_hello_command(hello: &Hello) -> Bytes {
+    debug!("Building hello command: {:?}", hello);
+    let mut payload = BytesMut::with_capacity(8);
+    payload.put_u16(hello.min_version);
+    payload.put_u16(hello.max_version);
+    payload.put_u32(hello.capabilities.0);
+    if let Some(slave_id) = &hello.slave_id {
+        payload.extend_from_slice(slave_id.as_bytes());
     }
+    build_command_frame(PacketType::Command, 0, Some(CommandType::Hello), &payload)
+}
 
-    frame.put_u32(payload.len() as u32);
-    frame.put_slice(payload);
-    frame.freeze()
+// Challenge carrying the master's nonce. The slave answers with an Auth frame
+// holding HMAC-SHA256(secret, nonce + slave ID) (32) followed by its slave ID.
+pub fn build_auth_challenge_command(nonce: &[u8]) -> Bytes {
+    debug!("Building auth challenge command");
+    build_command_frame(PacketType::Command, 0, Some(CommandType::Auth), nonce)
 }
 
-pub fn build_speed_test_command(url: &str) -> Bytes {
-    debug!("Building speed test command for URL: {}", url);
-    build_command_frame(
-        PacketType::Command,
-        0,
-        Some(CommandType::SpeedCheck),
-        url.as_bytes(),
-    )
+// Slave ID and MAC from a slave's Auth reply
+pub fn parse_auth_response(payload: &[u8]) -> Option<(String, &[u8])> {
+    let (mac, slave_id) = payload.split_at_checked(32)?;
+    let slave_id = String::from_utf8(slave_id.to_vec()).ok()?;
+    (!slave_id.is_empty()).then_some((slave_id, mac))
 }
 
 pub fn build_version_check_command() -> Bytes {
@@ -107,6 +414,70 @@ pub fn build_init_session_command(session_id: u32, payload: &str) -> Bytes {
     )
 }
 
+// Asks the slave to open a UDP socket for the session, acked like InitSession
+pub fn build_udp_associate_command(session_id: u32) -> Bytes {
+    debug!("Building UDP associate command: session_id={}", session_id);
+    build_command_frame(PacketType::Command, session_id, Some(CommandType::UdpAssociate), &[])
+}
+
+// Asks the slave to listen for one inbound connection from the given peer.
+// The slave acks twice: once listening with its bound address, then with the
+// address of the peer that connected.
+pub fn build_bind_command(session_id: u32, payload: &str) -> Bytes {
+    debug!("Building bind command: session_id={}, payload={}", session_id, payload);
+    build_command_frame(
+        PacketType::Command,
+        session_id,
+        Some(CommandType::Bind),
+        payload.as_bytes(),
+    )
+}
+
+// Grants the peer `credit` more bytes of data on the session
+pub fn build_window_update_command(session_id: u32, credit: u32) -> Bytes {
+    debug!(
+        "Building window update command: session_id={}, credit={}",
+        session_id, credit
+    );
+    build_command_frame(
+        PacketType::Command,
+        session_id,
+        Some(CommandType::WindowUpdate),
+        &credit.to_be_bytes(),
+    )
+}
+
+// A slave with a slave ID, flow control and RESUME that reconnects within the
+// grace period keeps its sessions. For each session it still holds it sends
+// ResumeSession carrying the bytes (u64) it has received on it; the master
+// answers in kind and both sides replay what the other is missing. Data is
+// kept until the peer credits it with WindowUpdate, and both windows restart
+// at INITIAL_WINDOW minus the replayed bytes.
+pub fn build_resume_session_command(session_id: u32, received: u64) -> Bytes {
+    debug!(
+        "Building resume session command: session_id={}, received={}",
+        session_id, received
+    );
+    build_command_frame(
+        PacketType::Command,
+        session_id,
+        Some(CommandType::ResumeSession),
+        &received.to_be_bytes(),
+    )
+}
+
+// Tells the peer the session is gone and its socket should be dropped
+pub fn build_close_session_command(session_id: u32) -> Bytes {
+    debug!("Building close session command: session_id={}", session_id);
+    build_command_frame(PacketType::Command, session_id, Some(CommandType::CloseSession), &[])
+}
+
+// Propagates a TCP FIN: the sender will not write any more data for this session
+pub fn build_half_close_command(session_id: u32) -> Bytes {
+    debug!("Building half close command: session_id={}", session_id);
+    build_command_frame(PacketType::Command, session_id, Some(CommandType::HalfClose), &[])
+}
+
 pub fn build_location_check_command(ip: &str) -> Bytes {
     debug!("Building location check command for IP: {}", ip);
     build_command_frame(
@@ -126,42 +497,27 @@ pub fn build_data_frame(session_id: u32, payload: &[u8]) -> Bytes {
     build_command_frame(PacketType::Data, session_id, None, payload)
 }
 
-pub fn parse_header(buffer: &[u8]) -> (Option<PacketType>, u32, usize, Option<CommandType>) {
-    debug!("Parsing header from buffer with length: {}", buffer.len());
-
-    if buffer.len() < 10 {
-        debug!("Buffer too short to parse header");
-        return (None, 0, 0, None);
-    }
-
-    let packet_type = PacketType::from_u8(buffer[0]);
-    let session_id = bytes_to_u32(&buffer[1..5]);
-    let payload_len = u32::from_be_bytes([buffer[6], buffer[7], buffer[8], buffer[9]]) as usize;
-
-    // Check for CommandType if the packet is a command
-    let command_type = if packet_type == Some(PacketType::Command) {
-        CommandType::from_u8(buffer[5])
-    } else {
-        None
-    };
-
+pub fn build_datagram_frame(session_id: u32, payload: &[u8]) -> Bytes {
     debug!(
-        "Parsed header: packet_type={:?}, session_id={}, payload_len={}, command_type={:?}",
-        packet_type, session_id, payload_len, command_type
+        "Building datagram frame: session_id={}, payload_len={}",
+        session_id,
+        payload.len()
     );
-
-    (packet_type, session_id, payload_len, command_type)
+    build_command_frame(PacketType::Datagram, session_id, None, payload)
 }
 
 pub async fn process_packet(
-    packet_type: Option<PacketType>,
-    command_type: Option<CommandType>,
-    payload: Bytes,
-    session_id: u32,
+    frame: Frame,
     slave: &Slave,
-    proxy_manager: &Arc<AsyncMutex<ProxyManager>>,
+    proxy_manager: &Arc<ProxyManager>,
     last_seen: &mut Instant,
 ) -> Result<(), std::io::Error> {
+    let Frame {
+        packet_type,
+        session_id,
+        command_type,
+        payload,
+    } = frame;
     debug!(
         "Processing packet: packet_type={:?}, command_type={:?}, session_id={}, payload_len={}",
         packet_type,
@@ -171,7 +527,7 @@ pub async fn process_packet(
     );
 
     match packet_type {
-        Some(PacketType::Command) => {
+        PacketType::Command => {
             match command_type {
                 Some(CommandType::Heartbeat) => {
                     // Update last_seen on valid heartbeat response
@@ -179,35 +535,260 @@ pub async fn process_packet(
                         *last_seen = Instant::now();
                         debug!("Received heartbeat response from slave {}", slave.ip_addr);
                     } else {
+                        debug!("Invalid heartbeat response from slave {}: {:?}", slave.ip_addr, payload);
+                    }
+                }
+                Some(CommandType::InitSession | CommandType::UdpAssociate | CommandType::Bind) => {
+                    match parse_session_ack(&payload) {
+                        Some(ack) => {
+                            debug!("Slave {} acknowledged session {}: {:?}", slave.ip_addr, session_id, ack);
+                            proxy_manager.route_to_client(session_id, ClientEvent::SessionAck(ack));
+                        }
+                        None => debug!(
+                            "Empty session ack from slave {} for session {}",
+                            slave.ip_addr, session_id
+                        ),
+                    }
+                }
+                Some(CommandType::CloseSession) => {
+                    debug!("Slave {} closed upstream of session {}", slave.ip_addr, session_id);
+                    proxy_manager.route_to_client(session_id, ClientEvent::Close);
+                }
+                Some(CommandType::HalfClose) => {
+                    debug!("Slave {} half-closed upstream of session {}", slave.ip_addr, session_id);
+                    proxy_manager.route_to_client(session_id, ClientEvent::HalfClose);
+                }
+                Some(CommandType::WindowUpdate) => match payload.get(..4) {
+                    Some(credit) => {
+                        let credit = u32::from_be_bytes([credit[0], credit[1], credit[2], credit[3]]);
+                        proxy_manager.route_to_client(session_id, ClientEvent::WindowUpdate(credit));
+                    }
+                    None => debug!(
+                        "Malformed window update from slave {} for session {}",
+                        slave.ip_addr, session_id
+                    ),
+                },
+                Some(CommandType::ResumeSession) => match payload.get(..8) {
+                    Some(received) => {
+                        let received = u64::from_be_bytes(received.try_into().unwrap());
                         debug!(
-                            "Invalid heartbeat response from slave {}: {:?}",
-                            slave.ip_addr, payload
+                            "Slave {} resumes session {} at offset {}",
+                            slave.ip_addr, session_id, received
                         );
+                        proxy_manager.route_to_client(session_id, ClientEvent::Resume(received));
                     }
-                }
+                    None => debug!(
+                        "Malformed resume from slave {} for session {}",
+                        slave.ip_addr, session_id
+                    ),
+                },
                 _ => debug!(
                     "Ignoring unsupported command packet from slave {}: {:?}",
                     slave.ip_addr, command_type
                 ),
             }
         }
-        Some(PacketType::Data) => {
+        PacketType::Data => {
             debug!(
                 "Routing data payload from slave {}: sid={}, bytes={}",
                 slave.ip_addr,
                 session_id,
                 payload.len()
             );
-            proxy_manager
-                .lock()
-                .await
-                .route_to_client(session_id, payload)
-                .await;
+            proxy_manager.route_to_client(session_id, ClientEvent::Data(payload));
         }
-        None => {
-            error!("Unknown PacketType received from slave: {}", slave.ip_addr);
+        PacketType::Datagram => {
+            debug!(
+                "Routing datagram from slave {}: sid={}, bytes={}",
+                slave.ip_addr,
+                session_id,
+                payload.len()
+            );
+            proxy_manager.route_to_client(session_id, ClientEvent::Datagram(payload));
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn frame_strategy() -> impl Strategy<Value = Frame> {
+        let header = prop_oneof![
+            Just((PacketType::Data, None)),
+            Just((PacketType::Datagram, None)),
+            (0x01u8..=0x0D).prop_map(|cmd| (PacketType::Command, CommandType::from_u8(cmd))),
+        ];
+        (header, any::<u32>(), proptest::collection::vec(any::<u8>(), 0..2048)).prop_map(
+            |((packet_type, command_type), session_id, payload)| Frame {
+                packet_type,
+                session_id,
+                command_type,
+                payload: Bytes::from(payload),
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn frames_round_trip(frames in proptest::collection::vec(frame_strategy(), 1..8), split in any::<usize>()) {
+            let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+            let mut encoded = BytesMut::new();
+            for frame in &frames {
+                codec.encode(frame.clone(), &mut encoded).unwrap();
+            }
+
+            // Deliver the stream in two arbitrary pieces
+            let split = split % (encoded.len() + 1);
+            let mut buffer = BytesMut::from(&encoded[..split]);
+            let mut decoded = Vec::new();
+            while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(frame);
+            }
+            buffer.extend_from_slice(&encoded[split..]);
+            while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(frame);
+            }
+
+            prop_assert_eq!(decoded, frames);
+            prop_assert!(buffer.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn close_frames_reach_the_session() {
+        use crate::proxy::Client;
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slave_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let client_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (slave, _slave_rx) = Slave::new("127.0.0.1".to_string(), slave_stream);
+
+        let manager = Arc::new(ProxyManager::new(1, std::time::Duration::from_secs(60)));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let src_addr = client_stream.local_addr().unwrap();
+        manager.clients.insert(
+            9,
+            Client::new(Arc::new(AsyncMutex::new(client_stream.into())), src_addr, tx),
+        );
+
+        let mut last_seen = Instant::now();
+        for command in [CommandType::HalfClose, CommandType::CloseSession] {
+            let frame = Frame {
+                packet_type: PacketType::Command,
+                session_id: 9,
+                command_type: Some(command),
+                payload: Bytes::new(),
+            };
+            process_packet(frame, &slave, &manager, &mut last_seen).await.unwrap();
+        }
+        assert!(matches!(rx.try_recv(), Ok(ClientEvent::HalfClose)));
+        assert!(matches!(rx.try_recv(), Ok(ClientEvent::Close)));
+    }
+
+    #[test]
+    fn max_frame_size_has_a_floor() {
+        assert_eq!(parse_max_frame_size("65536"), Ok(MIN_MAX_FRAME_SIZE));
+        assert_eq!(parse_max_frame_size("1048576"), Ok(DEFAULT_MAX_FRAME_SIZE));
+        assert!(parse_max_frame_size("65535").is_err());
+        assert!(parse_max_frame_size("0").is_err());
+        assert!(parse_max_frame_size("1M").is_err());
+    }
+
+    #[test]
+    fn session_ack_payloads() {
+        let statuses = [
+            (0x00, SessionStatus::Success),
+            (0x01, SessionStatus::GeneralFailure),
+            (0x02, SessionStatus::Refused),
+            (0x03, SessionStatus::HostUnreachable),
+            (0x04, SessionStatus::NetworkUnreachable),
+            (0x05, SessionStatus::TtlExpired),
+            (0x06, SessionStatus::NotAllowed),
+            (0x07, SessionStatus::GeneralFailure),
+            (0xff, SessionStatus::GeneralFailure),
+        ];
+        for (byte, status) in statuses {
+            let ack = parse_session_ack(&[byte]).unwrap();
+            assert_eq!(ack.status, status, "status byte {:#04x}", byte);
+            assert_eq!(ack.bound_addr, None);
+        }
+        assert!(parse_session_ack(&[]).is_none());
+
+        let v4: SocketAddr = "192.0.2.1:8080".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let addresses: [(&[u8], Option<SocketAddr>); 6] = [
+            (b"\x00\x01\xc0\x00\x02\x01\x1f\x90", Some(v4)),
+            (
+                b"\x00\x04\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x01\xbb",
+                Some(v6),
+            ),
+            // Truncated address or port
+            (b"\x00\x01\xc0\x00\x02", None),
+            (b"\x00\x04\x20\x01\x0d\xb8", None),
+            // Domain names and unknown address types are not bound addresses
+            (b"\x00\x03\x04host\x00\x50", None),
+            (b"\x00\x09\x01\x02", None),
+        ];
+        for (payload, bound_addr) in addresses {
+            let ack = parse_session_ack(payload).unwrap();
+            assert_eq!(ack.status, SessionStatus::Success);
+            assert_eq!(ack.bound_addr, bound_addr, "payload {:?}", payload);
+        }
+    }
+
+    #[test]
+    fn hello_negotiation() {
+        let slave = Hello {
+            min_version: 2,
+            max_version: 5,
+            capabilities: Capabilities::UDP.union(Capabilities::COMPRESSION),
+            slave_id: Some("edge-7".to_string()),
+        };
+        assert_eq!(MASTER_HELLO.negotiate(&slave), Some((2, Capabilities::UDP)));
+
+        let frame = build_hello_command(&slave);
+        assert_eq!(parse_hello(&frame[HEADER_LEN..]), Some(slave.clone()));
+        let frame = build_hello_command(&MASTER_HELLO);
+        assert_eq!(parse_hello(&frame[HEADER_LEN..]), Some(MASTER_HELLO));
+
+        let newer = Hello {
+            min_version: 3,
+            ..slave
+        };
+        assert_eq!(MASTER_HELLO.negotiate(&newer), None);
+    }
+
+    #[test]
+    fn rejects_bad_headers_before_payload() {
+        let mut codec = FrameCodec::new(16);
+
+        let mut buffer = BytesMut::from(&[0x01, 0, 0, 0, 1, 0x03, 0, 0, 0, 17][..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(FrameError::FrameTooLarge(17))));
+
+        let mut buffer = BytesMut::from(&[0x07, 0, 0, 0, 1, 0x00, 0, 0, 0, 0][..]);
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(FrameError::UnknownPacketType(0x07))
+        ));
+
+        let mut buffer = BytesMut::from(&[0x01, 0, 0, 0, 1, 0x7f, 0, 0, 0, 0][..]);
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(FrameError::UnknownCommand(0x7f))
+        ));
+
+        let frame = Frame {
+            packet_type: PacketType::Data,
+            session_id: 1,
+            command_type: None,
+            payload: Bytes::from(vec![0u8; 17]),
+        };
+        assert!(codec.encode(frame, &mut BytesMut::new()).is_err());
+    }
+}
@@ -1,28 +1,65 @@
+use crate::auth::{ClientAuth, User};
 use crate::buffer_pool::ShardedBufferPool;
+use crate::http::{handle_http_handshake, send_http_reply};
 use crate::load_balancing::{BalanceCtx, Balancer, Strategy};
 use crate::metrics::Metrics;
 use crate::packet::{
-    build_data_frame, build_heartbeat_command, build_init_session_command, parse_header,
-    process_packet,
+    build_bind_command, build_close_session_command, build_data_frame, build_datagram_frame, build_half_close_command,
+    build_heartbeat_command, build_init_session_command, build_resume_session_command, build_udp_associate_command,
+    build_window_update_command, process_packet, Capabilities, FrameCodec, SessionAck, SessionStatus, INITIAL_WINDOW,
+    LEGACY_PROTOCOL_VERSION,
 };
-use crate::socks5::handle_client_handshake;
+use crate::routing::{location_eq, RouteRequest};
+use crate::socks4::send_socks4_reply;
+use crate::socks5::{
+    handle_socks_handshake, reply_code, send_reply, strip_udp_header, ClientProtocol, Socks5Command,
+    REPLY_COMMAND_NOT_SUPPORTED, REPLY_GENERAL_FAILURE, REPLY_NETWORK_UNREACHABLE, REPLY_NOT_ALLOWED, REPLY_SUCCEEDED,
+};
+use crate::tls::{ClientStream, SlaveReader, SlaveStream, SlaveWriter};
 use crate::utils::{hash_ip, CLIENT_REQUEST_TIMEOUT};
 
+use arc_swap::ArcSwap;
 use bytes::{Buf, Bytes, BytesMut};
 use dashmap::DashMap;
 use log::{debug, error, info, trace, warn};
-use std::net::IpAddr;
-use std::str::FromStr;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex as AsyncMutex, Semaphore};
+use std::collections::{HashSet, VecDeque};
+use std::io::IoSlice;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{mpsc, Mutex as AsyncMutex, OwnedMutexGuard, Semaphore};
 use tokio::time::{timeout, Duration, Instant};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
 };
+use tokio_util::codec::Decoder;
 
 const KEEP_ALIVE_DURATION: u64 = 10;
+const AFFINITY_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+const UDP_ASSOCIATION_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+// Frames queued for a slave are written out together, up to this many per syscall
+const MAX_WRITE_BATCH: usize = 64;
+// Consumed bytes are credited back to the slave in batches of this size
+const WINDOW_UPDATE_THRESHOLD: usize = INITIAL_WINDOW as usize / 4;
+// Flow-controlled slaves never get further ahead than INITIAL_WINDOW. Legacy
+// slaves cannot be throttled per session, so a client this far behind is reset.
+const MAX_PENDING_BYTES: usize = 8 * 1024 * 1024;
+// How long a disconnected slave's token, sticky sessions and in-flight
+// sessions are kept for it to reconnect. Below CLIENT_REQUEST_TIMEOUT so idle
+// sessions can still be resumed.
+const RESUME_GRACE: Duration = Duration::from_secs(20);
+// Replaying data relies on the slave's WindowUpdate credit
+const RESUMABLE: Capabilities = Capabilities::RESUME.union(Capabilities::FLOW_CONTROL);
+
+// Protocol spoken by clients on a listener
+#[derive(Debug, Clone, Copy)]
+pub enum Frontend {
+    Socks,
+    Http,
+}
 
 #[derive(Clone)]
 pub struct Slave {
@@ -30,23 +67,49 @@ pub struct Slave {
     pub id_token: u32,
     pub version: Option<String>,
     pub location: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    // Negotiated in the hello exchange, legacy slaves get v1 and no capabilities
+    pub protocol_version: u16,
+    pub capabilities: Capabilities,
+    // Sent in the hello by slaves that open several connections
+    pub slave_id: Option<String>,
+    // The ID is backed by a client certificate or the slave's own secret,
+    // not just claimed, so it may take over a departed slave's token
+    pub id_verified: bool,
+    // Every connection registered under the slave's ID, this one included
+    group: Arc<ConnectionGroup>,
     // Weight for round robin
     net_speed: f64,
-    stream: Arc<AsyncMutex<TcpStream>>,
+    // Read by the slave's I/O task. Once registered, the write half belongs
+    // to its writer task, which drains `tx`.
+    reader: Arc<AsyncMutex<SlaveReader>>,
+    writer: Arc<AsyncMutex<SlaveWriter>>,
     // Sender to receive data from clients
     tx: mpsc::Sender<Bytes>,
 }
 
 impl Slave {
-    pub fn new(ip_addr: String, stream: TcpStream) -> (Self, mpsc::Receiver<Bytes>) {
+    pub fn new(ip_addr: String, stream: impl Into<SlaveStream>) -> (Self, mpsc::Receiver<Bytes>) {
         let (tx, rx) = mpsc::channel::<Bytes>(500);
+        let (reader, writer) = stream.into().into_split();
         let slave = Self {
             ip_addr,
             id_token: 0,
             version: None,
             location: None,
+            region: None,
+            city: None,
+            asn: None,
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Capabilities::default(),
+            slave_id: None,
+            id_verified: false,
+            group: Arc::new(ConnectionGroup::default()),
             net_speed: 0.0,
-            stream: Arc::new(AsyncMutex::new(stream)),
+            reader: Arc::new(AsyncMutex::new(reader)),
+            writer: Arc::new(AsyncMutex::new(writer)),
             tx,
         };
         (slave, rx)
@@ -54,16 +117,15 @@ impl Slave {
 
     // Helper method to read from the slave stream
     pub async fn read_stream(&self, buffer: &mut BytesMut) -> Result<usize, std::io::Error> {
-        let mut slave_stream = self.stream.lock().await;
-        slave_stream.read_buf(buffer).await
+        let mut reader = self.reader.lock().await;
+        reader.read_buf(buffer).await
     }
 
-    // Helper method to write to the slave stream
+    // Write directly to the slave, only used before its writer task starts
     pub async fn write_stream(&self, frame: &Bytes) -> Result<(), std::io::Error> {
-        let mut stream = self.stream.lock().await;
-        stream.write_all(frame).await?;
-        stream.flush().await?;
-        Ok(())
+        let mut writer = self.writer.lock().await;
+        writer.write_all(frame).await?;
+        writer.flush().await
     }
 
     pub fn set_version(&mut self, version: String) {
@@ -74,38 +136,251 @@ impl Slave {
         self.location = Some(location);
     }
 
+    pub fn set_geo(&mut self, region: Option<String>, city: Option<String>, asn: Option<u32>) {
+        self.region = region;
+        self.city = city;
+        self.asn = asn;
+    }
+
     pub fn set_speed(&mut self, speed: f64) {
         self.net_speed = speed;
     }
+
+    pub fn set_protocol(&mut self, version: u16, capabilities: Capabilities) {
+        self.protocol_version = version;
+        self.capabilities = capabilities;
+    }
+
+    // Further connections of a registered slave share its location and speed
+    pub fn inherit_profile(&mut self, primary: &Slave) {
+        self.location = primary.location.clone();
+        self.region = primary.region.clone();
+        self.city = primary.city.clone();
+        self.asn = primary.asn;
+        self.net_speed = primary.net_speed;
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.group.connections.read().unwrap().len()
+    }
+
+    // Hand a new session the least loaded of the slave's connections
+    fn handle(&self) -> SlaveHandle {
+        match self.group.least_loaded() {
+            Some(connection) => {
+                connection.sessions.fetch_add(1, Ordering::Relaxed);
+                SlaveHandle {
+                    token: self.id_token,
+                    tx: connection.tx,
+                    capabilities: connection.capabilities,
+                    _load: Some(Arc::new(SessionLoad(connection.sessions))),
+                }
+            }
+            None => SlaveHandle {
+                token: self.id_token,
+                tx: self.tx.clone(),
+                capabilities: self.capabilities,
+                _load: None,
+            },
+        }
+    }
+}
+
+// One of the TCP connections a slave registered under its ID
+#[derive(Clone)]
+struct GroupConnection {
+    tx: mpsc::Sender<Bytes>,
+    capabilities: Capabilities,
+    // Sessions currently carried by the connection
+    sessions: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+struct ConnectionGroup {
+    connections: RwLock<Vec<GroupConnection>>,
+    // Connections whose I/O task is running. Per-slave metrics count the
+    // group from its first one starting to its last one ending.
+    running: AtomicUsize,
+}
+
+impl ConnectionGroup {
+    fn add(&self, slave: &Slave) {
+        self.connections.write().unwrap().push(GroupConnection {
+            tx: slave.tx.clone(),
+            capabilities: slave.capabilities,
+            sessions: Arc::default(),
+        });
+    }
+
+    // Returns how many connections are left
+    fn remove(&self, slave: &Slave) -> usize {
+        let mut connections = self.connections.write().unwrap();
+        connections.retain(|connection| !connection.tx.same_channel(&slave.tx));
+        connections.len()
+    }
+
+    // Fewest sessions first, then the shortest write queue
+    fn least_loaded(&self) -> Option<GroupConnection> {
+        self.connections
+            .read()
+            .unwrap()
+            .iter()
+            .min_by_key(|connection| {
+                (
+                    connection.sessions.load(Ordering::Relaxed),
+                    connection.tx.max_capacity() - connection.tx.capacity(),
+                )
+            })
+            .cloned()
+    }
+}
+
+// Counts a session against its connection until the last handle is dropped
+#[derive(Debug)]
+struct SessionLoad(Arc<AtomicUsize>);
+
+impl Drop for SessionLoad {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// What a client session needs from the slave carrying it
+#[derive(Debug, Clone)]
+pub struct SlaveHandle {
+    pub token: u32,
+    pub tx: mpsc::Sender<Bytes>,
+    pub capabilities: Capabilities,
+    _load: Option<Arc<SessionLoad>>,
+}
+
+impl SlaveHandle {
+    pub fn supports(&self, capability: Capabilities) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    // Queue a frame for the slave, false if its connection is gone or stuck
+    pub async fn send(&self, frame: Bytes) -> bool {
+        matches!(timeout(CLIENT_REQUEST_TIMEOUT, self.tx.send(frame)).await, Ok(Ok(())))
+    }
+}
+
+// Events routed from a slave to the client session they belong to
+#[derive(Debug)]
+pub enum ClientEvent {
+    // Result of the slave's connect attempt for a new session
+    SessionAck(SessionAck),
+    Data(Bytes),
+    // UDP reply for an associate session: ATYP + address + port + data
+    Datagram(Bytes),
+    // Upstream sent FIN, no more data will follow
+    HalfClose,
+    // Upstream is gone, tear the client socket down
+    Close,
+    // The slave may send this many more bytes on the session
+    WindowUpdate(u32),
+    // The client fell too far behind and was dropped by the router
+    Reset,
+    // The slave disconnected but may reconnect and resume the session
+    Detached,
+    // The reconnected slave has received this many bytes of the session
+    Resume(u64),
 }
 
 #[derive(Clone)]
 pub struct Client {
-    stream: Arc<AsyncMutex<TcpStream>>,
-    to_client_tx: mpsc::Sender<Bytes>,
+    stream: Arc<AsyncMutex<ClientStream>>,
+    // Address the client connects from, used for balancing and logging
+    pub src_addr: SocketAddr,
+    to_client_tx: mpsc::UnboundedSender<ClientEvent>,
+    // Bytes routed to the session that it has not written out yet
+    pending: Arc<AtomicUsize>,
+    // Token of the slave carrying the session once one is selected
+    slave_token: Option<u32>,
+    // Queue of the slave connection the session's frames go out on
+    slave_tx: Option<mpsc::Sender<Bytes>>,
 }
 
 impl Client {
-    pub fn new(stream: Arc<AsyncMutex<TcpStream>>, to_client_tx: mpsc::Sender<Bytes>) -> Self {
+    pub fn new(
+        stream: Arc<AsyncMutex<ClientStream>>,
+        src_addr: SocketAddr,
+        to_client_tx: mpsc::UnboundedSender<ClientEvent>,
+    ) -> Self {
         Self {
             stream,
+            src_addr,
             to_client_tx,
+            pending: Arc::new(AtomicUsize::new(0)),
+            slave_token: None,
+            slave_tx: None,
+        }
+    }
+}
+
+// Holds one of a user's concurrent session slots until dropped
+pub struct UserSessionGuard {
+    sessions: Arc<DashMap<String, usize>>,
+    username: String,
+}
+
+impl Drop for UserSessionGuard {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.sessions.get_mut(&self.username) {
+            *count = count.saturating_sub(1);
         }
+        self.sessions.remove_if(&self.username, |_, count| *count == 0);
     }
 }
 
+// A session key pinned to one slave until it expires or the slave goes away
+#[derive(Debug, Clone, Copy)]
+pub struct Affinity {
+    pub token: u32,
+    pub expires_at: Instant,
+}
+
+// Balancers over one version of the slave set. Topology changes publish a
+// new snapshot instead of mutating this one, so selection never waits.
+struct BalancerSnapshot {
+    all: Balancer,
+    // Balancers over the slaves matching a location filter, keyed by filter.
+    // Per-country ones are built with the snapshot, finer filters on demand.
+    by_location: DashMap<String, Balancer>,
+}
+
+// A slave that lost its last connection, kept until `expires_at`
+#[derive(Debug, Clone, Copy)]
+struct Departed {
+    token: u32,
+    expires_at: Instant,
+}
+
+// Shared by every slave and client task without an outer lock: lookups go
+// through the DashMaps and the current balancer snapshot
 pub struct ProxyManager {
-    pub slaves: DashMap<String, Slave>, // ID String -> Slave
-    pub clients: DashMap<u32, Client>,  // Map SessionId -> Client
+    pub slaves: DashMap<String, Slave>,         // ID String -> Slave
+    pub clients: DashMap<u32, Client>,          // Map SessionId -> Client
+    user_sessions: Arc<DashMap<String, usize>>, // Username -> active sessions
 
     // Load balancing strategy
-    pub balancer: Arc<AsyncMutex<Balancer>>,
+    balancers: ArcSwap<BalancerSnapshot>,
     pub balancing_strategy: Strategy,
+    // Serialises slave add/remove so snapshots are published in order
+    topology: Mutex<()>,
+    // Slave ID -> token of the slave its connections are grouped under
+    slave_ids: DashMap<String, u32>,
+    // Slaves that went away and may still reclaim their token: ID -> token
+    departed: DashMap<String, Departed>,
     token_counter: AtomicU32,
+
+    // Sticky sessions: session key -> pinned slave
+    pub affinities: DashMap<String, Affinity>,
+    sticky_ttl: Duration,
 }
 
 impl ProxyManager {
-    pub fn new(client_assign_mode: u8) -> Self {
+    pub fn new(client_assign_mode: u8, sticky_ttl: Duration) -> Self {
         let strategy = match client_assign_mode {
             1 => Strategy::IpHash,
             2 => Strategy::RoundRobin,
@@ -115,9 +390,18 @@ impl ProxyManager {
         ProxyManager {
             slaves: DashMap::new(),
             clients: DashMap::new(),
-            balancer: Arc::new(AsyncMutex::new(Balancer::new(strategy, &[], &[]))),
+            user_sessions: Arc::new(DashMap::new()),
+            balancers: ArcSwap::from_pointee(BalancerSnapshot {
+                all: Balancer::new(strategy, &[], &[]),
+                by_location: DashMap::new(),
+            }),
             balancing_strategy: strategy,
+            topology: Mutex::new(()),
+            slave_ids: DashMap::new(),
+            departed: DashMap::new(),
             token_counter: AtomicU32::new(0),
+            affinities: DashMap::new(),
+            sticky_ttl,
         }
     }
 
@@ -125,189 +409,542 @@ impl ProxyManager {
         self.token_counter.fetch_add(1, Ordering::Relaxed)
     }
 
-    pub async fn update_balancer(&mut self) {
-        let weights: Vec<u32> = self
+    // Balancer over the slaves accepted by the filter, None if there are none
+    fn build_balancer(&self, filter: impl Fn(&Slave) -> bool) -> Option<Balancer> {
+        let (weights, tokens): (Vec<u32>, Vec<u32>) = self
             .slaves
             .iter()
-            .map(|entry| entry.value().net_speed as u32)
-            .collect();
+            .filter(|entry| filter(entry.value()))
+            .map(|entry| (entry.value().net_speed as u32, entry.value().id_token))
+            .unzip();
+
+        if tokens.is_empty() {
+            return None;
+        }
+        Some(Balancer::new(self.balancing_strategy, &weights, &tokens))
+    }
 
-        let tokens: Vec<u32> = self
+    // Only called with the topology lock held
+    fn update_balancer(&self) {
+        let all = self
+            .build_balancer(|_| true)
+            .unwrap_or_else(|| Balancer::new(self.balancing_strategy, &[], &[]));
+
+        let by_location = DashMap::new();
+        let countries: HashSet<String> = self
             .slaves
             .iter()
-            .map(|entry| entry.value().id_token as u32)
+            .filter_map(|entry| entry.value().location.clone())
             .collect();
 
-        let mut balancer = self.balancer.lock().await;
-        *balancer = Balancer::new(self.balancing_strategy, &weights, &tokens);
+        for country in countries {
+            let route = RouteRequest {
+                country: Some(country),
+                ..Default::default()
+            };
+            if let (Some(key), Some(balancer)) = (
+                location_filter_key(&route, &[]),
+                self.build_balancer(|slave| slave_matches(slave, &route, &[])),
+            ) {
+                by_location.insert(key, balancer);
+            }
+        }
+
+        self.balancers.store(Arc::new(BalancerSnapshot { all, by_location }));
     }
 
-    pub async fn add_slave(&mut self, mut slave: Slave) {
-        let new_token = self.generate_token();
+    // Balancer restricted to the requested location, or the main one when unfiltered
+    fn balancer_for(&self, route: &RouteRequest, allowed_locations: &[String]) -> Option<Balancer> {
+        let balancers = self.balancers.load();
+        let key = match location_filter_key(route, allowed_locations) {
+            Some(key) => key,
+            None => return Some(balancers.all.clone()),
+        };
+
+        if let Some(balancer) = balancers.by_location.get(&key) {
+            return Some(balancer.clone());
+        }
+
+        // Cached in the snapshot it was looked up in, a newer one starts empty
+        let balancer = self.build_balancer(|slave| slave_matches(slave, route, allowed_locations))?;
+        balancers.by_location.insert(key, balancer.clone());
+        Some(balancer)
+    }
+
+    // Registered slave that connections with this ID join
+    pub fn slave_by_id(&self, slave_id: &str) -> Option<Slave> {
+        let token = *self.slave_ids.get(slave_id)?;
+        self.slaves.get(&token.to_string()).map(|slave| slave.clone())
+    }
+
+    // Register a verified connection, either as a new slave or as one more
+    // connection of the slave sharing its ID. Returns it with its token set.
+    pub fn add_slave(&self, mut slave: Slave) -> Slave {
+        let _topology = self.topology.lock().unwrap();
+
+        if let Some(primary) = slave.slave_id.as_deref().and_then(|id| self.slave_by_id(id)) {
+            slave.id_token = primary.id_token;
+            slave.group = Arc::clone(&primary.group);
+            slave.group.add(&slave);
+            debug!(
+                "Connection from {} joined slave {}, now {} connections",
+                slave.ip_addr,
+                primary.id_token,
+                primary.connection_count()
+            );
+            return slave;
+        }
+
+        // A slave reconnecting within the grace period gets its token back,
+        // provided it proved the ID is its own. Anyone else merely claiming
+        // it is registered without the ID.
+        self.expire_departed();
+        let claimed = slave
+            .slave_id
+            .as_deref()
+            .is_some_and(|id| self.departed.contains_key(id));
+        if claimed && !slave.id_verified {
+            warn!(
+                "Slave {} claims departed ID {:?} without proving it, ignoring the ID",
+                slave.ip_addr, slave.slave_id
+            );
+            slave.slave_id = None;
+        }
+        let reclaimed = slave
+            .slave_id
+            .as_deref()
+            .and_then(|id| self.departed.remove(id))
+            .map(|(_, departed)| departed.token);
+        let new_token = reclaimed.unwrap_or_else(|| self.generate_token());
+        if reclaimed.is_some() {
+            info!("Slave {} reconnected and reclaimed token {}", slave.ip_addr, new_token);
+        }
+
         slave.id_token = new_token;
+        slave.group.add(&slave);
+        if let Some(slave_id) = &slave.slave_id {
+            self.slave_ids.insert(slave_id.clone(), new_token);
+        }
+
+        self.slaves.insert(new_token.to_string(), slave.clone());
+        self.update_balancer();
+        slave
+    }
+
+    // Drop one connection, and the slave with it once no connection is left
+    pub fn remove_slave(&self, slave: &Slave) {
+        let _topology = self.topology.lock().unwrap();
+        let resumable = slave.slave_id.is_some() && slave.capabilities.contains(RESUMABLE);
+        let remaining = slave.group.remove(slave);
+        if remaining > 0 {
+            debug!("Slave {} still has {} connections", slave.id_token, remaining);
+            // Only the sessions on the dropped connection lost their link
+            self.notify_sessions(
+                |client| client.slave_tx.as_ref().is_some_and(|tx| tx.same_channel(&slave.tx)),
+                || match resumable {
+                    true => ClientEvent::Detached,
+                    false => ClientEvent::Close,
+                },
+            );
+            return;
+        }
+
+        self.slaves.remove(&slave.id_token.to_string());
+        match &slave.slave_id {
+            // Keep the token and sticky sessions around for a reconnect
+            Some(slave_id) => {
+                self.departed.insert(
+                    slave_id.clone(),
+                    Departed {
+                        token: slave.id_token,
+                        expires_at: Instant::now() + RESUME_GRACE,
+                    },
+                );
+            }
+            None => {
+                // Sessions pinned to this slave get re-assigned on their next connect
+                self.affinities.retain(|_, affinity| affinity.token != slave.id_token);
+            }
+        }
+        self.update_balancer();
+
+        let token = slave.id_token;
+        self.notify_sessions(
+            |client| client.slave_token == Some(token),
+            || match resumable {
+                true => ClientEvent::Detached,
+                false => ClientEvent::Close,
+            },
+        );
+    }
+
+    // Forget departed slaves whose grace period ran out, closing the sessions
+    // still waiting for them
+    pub fn purge_departed_slaves(&self) {
+        let _topology = self.topology.lock().unwrap();
+        self.expire_departed();
+    }
+
+    // Only called with the topology lock held
+    fn expire_departed(&self) {
+        let now = Instant::now();
+        let expired: Vec<(String, u32)> = self
+            .departed
+            .iter()
+            .filter(|entry| entry.expires_at <= now)
+            .map(|entry| (entry.key().clone(), entry.token))
+            .collect();
+
+        for (slave_id, token) in expired {
+            info!(
+                "Slave {} did not reconnect in time, releasing token {}",
+                slave_id, token
+            );
+            self.departed.remove(&slave_id);
+            self.slave_ids.remove_if(&slave_id, |_, t| *t == token);
+            self.affinities.retain(|_, affinity| affinity.token != token);
+            self.notify_sessions(|client| client.slave_token == Some(token), || ClientEvent::Close);
+        }
+    }
+
+    fn is_departed(&self, token: u32) -> bool {
+        self.departed.iter().any(|entry| entry.token == token)
+    }
+
+    // Send an event to every session matching `carried`
+    fn notify_sessions(&self, carried: impl Fn(&Client) -> bool, event: impl Fn() -> ClientEvent) {
+        let sessions: Vec<u32> = self
+            .clients
+            .iter()
+            .filter(|entry| carried(entry.value()))
+            .map(|entry| *entry.key())
+            .collect();
+        for session_id in sessions {
+            self.route_to_client(session_id, event());
+        }
+    }
+
+    // A new handle on a registered slave, for sessions resuming on it
+    pub fn slave_handle(&self, token: u32) -> Option<SlaveHandle> {
+        self.slaves.get(&token.to_string()).map(|slave| slave.handle())
+    }
+
+    // Reserve a session slot for the user, None if it is at its session limit
+    pub fn acquire_user_session(&self, user: &User) -> Option<UserSessionGuard> {
+        let mut count = self.user_sessions.entry(user.username.clone()).or_insert(0);
+        if user.max_sessions.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+
+        Some(UserSessionGuard {
+            sessions: Arc::clone(&self.user_sessions),
+            username: user.username.clone(),
+        })
+    }
 
-        self.slaves.insert(new_token.to_string(), slave);
-        self.update_balancer().await;
+    // Sessions named in the username are pinned per user, otherwise sticky
+    // mode pins by client IP
+    fn affinity_key(&self, client_addr: &SocketAddr, username: Option<&str>, route: &RouteRequest) -> Option<String> {
+        match (&route.session, username) {
+            (Some(session), Some(username)) => Some(format!("{}:{}", username, session)),
+            (Some(session), None) => Some(session.clone()),
+            (None, _) if self.balancing_strategy == Strategy::IpHash => Some(client_addr.ip().to_string()),
+            (None, _) => None,
+        }
     }
 
-    pub async fn remove_slave(&mut self, slave_id_token: &u32) {
-        self.slaves.remove(&slave_id_token.to_string());
-        self.update_balancer().await;
+    // Drop expired sticky sessions, returning the remaining TTL of the live ones
+    pub fn purge_expired_affinities(&self) -> Vec<Duration> {
+        let now = Instant::now();
+        self.affinities.retain(|_, affinity| affinity.expires_at > now);
+        self.affinities.iter().map(|entry| entry.expires_at - now).collect()
     }
 
-    // Get tx of avaiable Slave using weighted round-robin
-    pub async fn get_available_slave_tx(
+    // Get tx of avaiable Slave using the configured strategy among the slaves
+    // matching the requested location
+    pub fn get_available_slave(
         &self,
-        client_ip: &String,
-        requested_location: Option<&String>,
-    ) -> Option<mpsc::Sender<Bytes>> {
+        client_addr: &SocketAddr,
+        username: Option<&str>,
+        route: &RouteRequest,
+        allowed_locations: &[String],
+    ) -> Result<SlaveHandle, SelectError> {
         trace!(
-            "Finding available slave for client IP: {}, Requested location: {:?}",
-            client_ip,
-            requested_location
+            "Finding available slave for client: {}, Route: {:?}, Allowed locations: {:?}",
+            client_addr,
+            route,
+            allowed_locations
         );
 
-        match IpAddr::from_str(client_ip) {
-            Ok(parsed_ip) => {
-                if let Some(token) = self
-                    .balancer
-                    .lock()
-                    .await
-                    .next(BalanceCtx { src_ip: &parsed_ip })
-                {
-                    if let Some(slave) = self.slaves.get(&token.0.to_string()) {
-                        debug!(
-                            "Found slave: {}, Token: {}, Location: {:?}",
-                            slave.ip_addr, token.0, slave.location
-                        );
-
-                        // Handle location filtering
-                        if let Some(requested_location) = requested_location {
-                            if let Some(slave_location) = &slave.location {
-                                if slave_location.eq_ignore_ascii_case(requested_location) {
-                                    return Some(slave.tx.clone());
-                                } else {
-                                    debug!(
-                                        "Location mismatch. Slave location: {}, Requested location: {}",
-                                        slave_location, requested_location
-                                    );
-                                }
-                            } else {
-                                trace!(
-                                    "Slave {} has no location data, skipping location match",
-                                    token.0
-                                );
-                            }
-                        } else {
-                            // No location requested, return the slave
-                            return Some(slave.tx.clone());
+        // Reuse the pinned slave while the sticky session is alive
+        let mut affinity_key = self.affinity_key(client_addr, username, route);
+        if let Some(key) = &affinity_key {
+            if let Some(affinity) = self.affinities.get(key).map(|entry| *entry) {
+                let alive = affinity.expires_at > Instant::now();
+                if alive {
+                    if let Some(slave) = self.slaves.get(&affinity.token.to_string()) {
+                        if slave_matches(&slave, route, allowed_locations) {
+                            trace!("Sticky session {} pinned to slave {}", key, affinity.token);
+                            return Ok(slave.handle());
                         }
-                    } else {
-                        trace!("No slave found for token: {}", token.0);
                     }
+                }
+
+                // Keep the pin while its slave may still come back, this
+                // connect is served elsewhere
+                if alive && self.is_departed(affinity.token) {
+                    trace!("Sticky session {} waits for slave {} to reconnect", key, affinity.token);
+                    affinity_key = None;
                 } else {
-                    trace!("Balancer did not return a valid token.");
+                    self.affinities.remove(key);
                 }
             }
-            Err(_) => {
-                error!("Invalid IP address format: {}", client_ip);
-            }
         }
-        None
+
+        let no_slave = || match location_filter_key(route, allowed_locations) {
+            Some(_) => SelectError::NoSlaveInLocation(location_label(route, allowed_locations)),
+            None => SelectError::NoSlaveAvailable,
+        };
+
+        let balancer = self.balancer_for(route, allowed_locations).ok_or_else(no_slave)?;
+
+        let token = balancer
+            .next(BalanceCtx {
+                src_addr: client_addr,
+                session_key: route.session.as_deref(),
+            })
+            .ok_or_else(no_slave)?;
+
+        let slave = self.slaves.get(&token.0.to_string()).ok_or_else(|| {
+            trace!("No slave found for token: {}", token.0);
+            no_slave()
+        })?;
+
+        debug!(
+            "Found slave: {}, Token: {}, Location: {:?}",
+            slave.ip_addr, token.0, slave.location
+        );
+
+        if let Some(key) = affinity_key {
+            let ttl = route.sticky_ttl.unwrap_or(self.sticky_ttl);
+            self.affinities.insert(
+                key,
+                Affinity {
+                    token: token.0,
+                    expires_at: Instant::now() + ttl,
+                },
+            );
+        }
+        Ok(slave.handle())
     }
 
-    // Route data to the appropriate client using the session ID
-    pub async fn route_to_client(&self, session_id: u32, payload: Bytes) {
-        if let Some(client) = self.clients.get(&session_id) {
-            if let Err(e) = client.to_client_tx.send(payload).await {
-                error!(
-                    "Failed to send to client mpsc channel: session id: {}: {}",
-                    session_id, e
-                );
-            }
-        } else {
-            trace!(
-                "No client found with session ID {}. Dropping data.",
-                session_id
+    // Route a slave event to the appropriate client using the session ID. This
+    // never waits on the client, so one stalled session cannot hold up the
+    // slave connection it shares with others.
+    pub fn route_to_client(&self, session_id: u32, event: ClientEvent) {
+        let Some(client) = self.clients.get(&session_id) else {
+            trace!("No client found with session ID {}. Dropping event.", session_id);
+            return;
+        };
+
+        let size = match &event {
+            ClientEvent::Data(payload) | ClientEvent::Datagram(payload) => payload.len(),
+            _ => 0,
+        };
+        let pending = client.pending.fetch_add(size, Ordering::AcqRel) + size;
+        if pending > MAX_PENDING_BYTES {
+            warn!(
+                "Client session {} is {} bytes behind, resetting it",
+                session_id, pending
             );
+            let tx = client.to_client_tx.clone();
+            drop(client);
+            self.clients.remove(&session_id);
+            let _ = tx.send(ClientEvent::Reset);
+            return;
+        }
+
+        if client.to_client_tx.send(event).is_err() {
+            trace!("Client session {} is gone, dropping event", session_id);
+        }
+    }
+}
+
+// Why no slave could be selected for a session
+#[derive(Debug)]
+pub enum SelectError {
+    NoSlaveAvailable,
+    NoSlaveInLocation(String),
+}
+
+impl std::fmt::Display for SelectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectError::NoSlaveAvailable => write!(f, "no slave available"),
+            SelectError::NoSlaveInLocation(location) => write!(f, "no slave in {}", location),
+        }
+    }
+}
+
+// Cache key of the location filter, None when any slave will do. The allowed
+// countries only narrow the search when no country was requested explicitly.
+fn location_filter_key(route: &RouteRequest, allowed_locations: &[String]) -> Option<String> {
+    let normalize = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(|v| v.to_ascii_lowercase().replace('_', " "))
+            .unwrap_or_else(|| "*".to_string())
+    };
+
+    let mut allowed: Vec<String> = match route.country {
+        Some(_) => Vec::new(),
+        None => allowed_locations.iter().map(|loc| loc.to_ascii_lowercase()).collect(),
+    };
+    allowed.sort();
+
+    if route.country.is_none()
+        && route.region.is_none()
+        && route.city.is_none()
+        && route.asn.is_none()
+        && allowed.is_empty()
+    {
+        return None;
+    }
+
+    Some(format!(
+        "{}|{}|{}|{}|{}",
+        normalize(&route.country),
+        normalize(&route.region),
+        normalize(&route.city),
+        route.asn.map(|asn| asn.to_string()).unwrap_or_else(|| "*".to_string()),
+        allowed.join(",")
+    ))
+}
+
+// Human readable location for errors, e.g. "DE/Munich AS3320"
+fn location_label(route: &RouteRequest, allowed_locations: &[String]) -> String {
+    let mut parts: Vec<&str> = [&route.country, &route.region, &route.city]
+        .into_iter()
+        .filter_map(|part| part.as_deref())
+        .collect();
+    let allowed = allowed_locations.join(",");
+    if parts.is_empty() && !allowed.is_empty() {
+        parts.push(&allowed);
+    }
+
+    let mut label = parts.join("/");
+    if let Some(asn) = route.asn {
+        if !label.is_empty() {
+            label.push(' ');
         }
+        label.push_str(&format!("AS{}", asn));
+    }
+    label
+}
+
+// Check a slave against the requested route and the user's allowed countries
+fn slave_matches(slave: &Slave, route: &RouteRequest, allowed_locations: &[String]) -> bool {
+    let allowed = allowed_locations.is_empty()
+        || slave
+            .location
+            .as_ref()
+            .is_some_and(|location| allowed_locations.iter().any(|loc| location.eq_ignore_ascii_case(loc)));
+
+    allowed
+        && route.matches_location(
+            slave.location.as_deref(),
+            slave.region.as_deref(),
+            slave.city.as_deref(),
+            slave.asn,
+        )
+}
+
+// Periodically expire sticky sessions and publish how many are left and
+// their remaining TTL range. Keys are client IPs and usernames, so they are
+// never exported themselves.
+pub async fn run_affinity_sweeper(proxy_manager: Arc<ProxyManager>, metrics: Arc<Metrics>) {
+    let mut ticker = tokio::time::interval(AFFINITY_SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        proxy_manager.purge_departed_slaves();
+        let live = proxy_manager.purge_expired_affinities();
+
+        let seconds = |ttl: Option<&Duration>| ttl.map_or(0, |ttl| ttl.as_secs() as i64);
+        metrics.sticky_sessions.set(live.len() as i64);
+        metrics.sticky_session_ttl_min_seconds.set(seconds(live.iter().min()));
+        metrics.sticky_session_ttl_max_seconds.set(seconds(live.iter().max()));
     }
 }
 
 // Function to handle a single slave's I/O operations for all clients using it (multiplexing)
 pub async fn handle_slave_io(
     slave: Slave,
-    mut cli_rx: mpsc::Receiver<Bytes>,
-    proxy_manager: Arc<AsyncMutex<ProxyManager>>,
+    leftover: BytesMut,
+    cli_rx: mpsc::Receiver<Bytes>,
+    proxy_manager: Arc<ProxyManager>,
     buffer_pool: Arc<ShardedBufferPool>,
     metrics: Arc<Metrics>,
+    max_frame_size: usize,
 ) -> Result<(), std::io::Error> {
     let shard_id = hash_ip(&slave.ip_addr);
     let mut buffer = buffer_pool.get_buffer(shard_id).await;
+    let mut codec = FrameCodec::new(max_frame_size);
 
+    let version_label = slave.version.clone().unwrap_or_else(|| "unknown".to_string());
     metrics.slave_active_connections.inc();
     metrics.slave_total_connections.inc();
+    if slave.group.running.fetch_add(1, Ordering::AcqRel) == 0 {
+        metrics.slaves_connected.inc();
+        metrics.slaves_by_version.with_label_values(&[&version_label]).inc();
+    }
 
     let max_heartbeat_timeout = Duration::from_secs(KEEP_ALIVE_DURATION * 3);
     let heartbeat_interval = Duration::from_secs(KEEP_ALIVE_DURATION);
     let mut last_seen = Instant::now();
     let mut last_heartbeat_sent = Instant::now();
 
-    loop {
-        tokio::select! {
-            // Handle incoming traffic from the slave
-            len = slave.read_stream(&mut buffer) => {
-                let len = len?;
-                if len == 0 {
-                    break;  // Slave connection closed
-                }
-
-                last_seen = Instant::now();
-
-                while buffer.len() >= 10 {
-                    let (current_packet_type, session_id, payload_len, current_command_type) = parse_header(&buffer);
-
-                    if current_packet_type.is_none() || buffer.len() < 10 + payload_len {
-                        break;
-                    }
-
-                    buffer.advance(10);
+    // Reads stay here while a dedicated task writes whatever sessions queue
+    let mut reader = slave.reader.lock().await;
+    let mut writer = tokio::spawn(run_slave_writer(Arc::clone(&slave.writer).lock_owned().await, cli_rx));
 
-                    // Process the packet with the current_packet_type and current_command_type
-                    let payload = buffer.split_to(payload_len).freeze();
+    // Frames that arrived right behind the handshake are already buffered
+    buffer.extend_from_slice(&leftover);
+    let mut protocol_error = !process_frames(&mut buffer, &mut codec, &slave, &proxy_manager, &mut last_seen).await;
 
-                    trace!("Processing packet from slave: {} | PacketType: {:?} | CommandType: {:?}",
-                          slave.ip_addr, current_packet_type, current_command_type);
-
-                    if let Err(_err) = process_packet(
-                        current_packet_type,
-                        current_command_type,
-                        payload,
-                        session_id,
-                        &slave,
-                        &proxy_manager,
-                        &mut last_seen,
-                    ).await {
-                        trace!("Critical error processing packet: {}. Exiting loop.", _err);
+    while !protocol_error {
+        tokio::select! {
+            // Handle incoming traffic from the slave
+            len = reader.read_buf(&mut buffer) => {
+                match len {
+                    Ok(0) => break,  // Slave connection closed
+                    Ok(_) => {}
+                    Err(e) => {
+                        debug!("Failed to read from slave {}: {}", slave.ip_addr, e);
                         break;
                     }
                 }
+
+                last_seen = Instant::now();
+                protocol_error = !process_frames(&mut buffer, &mut codec, &slave, &proxy_manager, &mut last_seen).await;
             }
 
-            // Handle traffic from clients
-            Some(payload) = cli_rx.recv() => {
-                if let Err(e) = slave.write_stream(&payload).await {
-                    error!("Failed to write to slave {}: {}", slave.ip_addr, e);
-                    break;
+            // The writer only stops when the connection broke
+            written = &mut writer => {
+                match written {
+                    Ok(Err(e)) => error!("Failed to write to slave {}: {}", slave.ip_addr, e),
+                    _ => error!("Writer for slave {} stopped", slave.ip_addr),
                 }
+                break;
             }
 
             // Periodically send heartbeat
             _ = tokio::time::sleep_until(last_heartbeat_sent + heartbeat_interval) => {
                 if last_heartbeat_sent.elapsed() >= heartbeat_interval {
+                    // A full queue means frames are flowing anyway
                     let heartbeat_command = build_heartbeat_command();
-                    if let Err(err) = slave.write_stream(&heartbeat_command).await {
-                        warn!("Failed to send heartbeat to slave {}: {}. Disconnecting.", slave.ip_addr, err);
+                    if let Err(mpsc::error::TrySendError::Closed(_)) = slave.tx.try_send(heartbeat_command) {
+                        warn!("Failed to send heartbeat to slave {}. Disconnecting.", slave.ip_addr);
                         break;
                     }
                     trace!("Sent heartbeat to slave {}", slave.ip_addr);
@@ -325,144 +962,605 @@ pub async fn handle_slave_io(
         }
     }
 
+    writer.abort();
+    drop(reader);
     buffer_pool.return_buffer(shard_id, buffer).await;
 
     // Handle disconnection
     info!("Slave {} disconnected", slave.ip_addr);
-    proxy_manager
-        .lock()
-        .await
-        .remove_slave(&slave.id_token)
-        .await;
+    proxy_manager.remove_slave(&slave);
 
     metrics.slave_active_connections.dec();
     metrics.slave_disconnections.inc();
+    if slave.group.running.fetch_sub(1, Ordering::AcqRel) == 1 {
+        metrics.slaves_connected.dec();
+        metrics.slaves_by_version.with_label_values(&[&version_label]).dec();
+    }
+
+    Ok(())
+}
+
+// Hand every complete frame in the buffer to process_packet. Returns false
+// once the stream can't be resynchronised after a bad frame.
+async fn process_frames(
+    buffer: &mut BytesMut,
+    codec: &mut FrameCodec,
+    slave: &Slave,
+    proxy_manager: &Arc<ProxyManager>,
+    last_seen: &mut Instant,
+) -> bool {
+    loop {
+        let frame = match codec.decode(buffer) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return true,
+            Err(e) => {
+                error!("Protocol error from slave {}: {}. Disconnecting.", slave.ip_addr, e);
+                return false;
+            }
+        };
+
+        trace!(
+            "Processing packet from slave: {} | PacketType: {:?} | CommandType: {:?}",
+            slave.ip_addr,
+            frame.packet_type,
+            frame.command_type
+        );
+
+        if let Err(_err) = process_packet(frame, slave, proxy_manager, last_seen).await {
+            trace!("Critical error processing packet: {}. Exiting loop.", _err);
+            return true;
+        }
+    }
+}
 
+// Drain the slave's queue, writing every frame that is ready in one vectored
+// write instead of a syscall per frame
+async fn run_slave_writer(
+    mut writer: OwnedMutexGuard<SlaveWriter>,
+    mut rx: mpsc::Receiver<Bytes>,
+) -> Result<(), std::io::Error> {
+    let mut batch = Vec::with_capacity(MAX_WRITE_BATCH);
+    while rx.recv_many(&mut batch, MAX_WRITE_BATCH).await > 0 {
+        write_frames(&mut *writer, &mut batch).await?;
+        // TLS holds records back until flushed
+        writer.flush().await?;
+        batch.clear();
+    }
     Ok(())
 }
 
+// write_all for a list of frames, resuming after partial vectored writes
+async fn write_frames<W: AsyncWrite + Unpin>(writer: &mut W, frames: &mut [Bytes]) -> Result<(), std::io::Error> {
+    let mut start = 0;
+    loop {
+        while frames.get(start).is_some_and(|frame| frame.is_empty()) {
+            start += 1;
+        }
+        if start == frames.len() {
+            return Ok(());
+        }
+
+        let slices: Vec<IoSlice> = frames[start..].iter().map(|frame| IoSlice::new(frame)).collect();
+        let mut written = writer.write_vectored(&slices).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+
+        while written > 0 {
+            let frame = &mut frames[start];
+            let len = written.min(frame.len());
+            frame.advance(len);
+            written -= len;
+            if frame.is_empty() {
+                start += 1;
+            }
+        }
+    }
+}
+
 // Function to handle traffic between a client and the slave
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_client_io(
     session_id: u32,
     client: Client,
-    mut client_rx: mpsc::Receiver<Bytes>,
-    proxy_manager: Arc<AsyncMutex<ProxyManager>>,
+    mut client_rx: mpsc::UnboundedReceiver<ClientEvent>,
+    proxy_manager: Arc<ProxyManager>,
     semaphore: Arc<Semaphore>,
     buffer_pool: Arc<ShardedBufferPool>,
+    auth: Arc<ClientAuth>,
+    frontend: Frontend,
 ) -> Result<(), std::io::Error> {
     let mut cli_stream = client.stream.lock().await;
 
-    // Process SOCKS5 handshake and extract username, destination information
-    let (username, dest_address, dest_port) = match handle_client_handshake(&mut cli_stream).await {
-        Ok(result) => {
+    // Process the handshake and extract user, destination information
+    let handshake = match frontend {
+        Frontend::Socks => handle_socks_handshake(&mut cli_stream, &auth).await,
+        Frontend::Http => handle_http_handshake(&mut cli_stream, &auth).await,
+    };
+    let mut request = match handshake {
+        Ok(request) => {
             debug!(
-                "Session {}: Handshake successful. Username: {:?}, Destination: {}:{}",
-                session_id, result.0, result.1, result.2
+                "Session {} from {}: Handshake successful. User: {:?}, Route: {:?}, Destination: {}:{}",
+                session_id,
+                client.src_addr,
+                request.user.as_ref().map(|u| &u.username),
+                request.route,
+                request.address,
+                request.port
             );
-            result
+            request
         }
         Err(e) => {
             debug!(
-                "Error during SOCKS5 handshake for session {}: {}",
-                session_id, e
+                "Error during {:?} handshake for session {}: {}",
+                frontend, session_id, e
             );
             return Err(e); // Exit if handshake fails
         }
     };
 
-    let slave_tx = proxy_manager
-        .lock()
-        .await
-        .get_available_slave_tx(
-            &cli_stream.local_addr()?.ip().to_string(),
-            username.as_ref(),
-        )
-        .await;
+    // Datagrams would bypass the TLS session the request came in on
+    if request.command == Socks5Command::UdpAssociate && cli_stream.is_tls() {
+        debug!("Refusing UDP associate over TLS for session {}", session_id);
+        reply_to_client(&mut cli_stream, request.protocol, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "UDP associate is not available over TLS",
+        ));
+    }
+
+    let user = request.user.as_ref();
+    let route = &request.route;
+
+    // Enforce the user's concurrent session limit for the lifetime of this session
+    let _user_session = match user {
+        Some(user) => match proxy_manager.acquire_user_session(user) {
+            Some(guard) => Some(guard),
+            None => {
+                debug!(
+                    "User {} reached its session limit, rejecting session {}",
+                    user.username, session_id
+                );
+                reply_to_client(&mut cli_stream, request.protocol, REPLY_NOT_ALLOWED, None).await?;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "User session limit reached",
+                ));
+            }
+        },
+        None => None,
+    };
 
-    let slave_tx = match slave_tx {
-        Some(tx) => tx,
-        None => {
+    let allowed_locations = user.map(|u| u.allowed_countries.as_slice()).unwrap_or_default();
+
+    // A requested country has to be one the user is allowed to use
+    if let Some(country) = &route.country {
+        if !allowed_locations.is_empty() && !allowed_locations.iter().any(|loc| location_eq(loc, country)) {
             debug!(
-                "No suitable slave found for session {} (username: {:?})",
-                session_id, username
+                "Session {} requested country {} outside of allowed locations {:?}",
+                session_id, country, allowed_locations
             );
+            reply_to_client(&mut cli_stream, request.protocol, REPLY_NOT_ALLOWED, None).await?;
             return Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "No suitable slave found",
+                std::io::ErrorKind::PermissionDenied,
+                "Requested country is not allowed",
             ));
         }
+    }
+
+    let slave = proxy_manager.get_available_slave(
+        &client.src_addr,
+        user.map(|u| u.username.as_str()),
+        route,
+        allowed_locations,
+    );
+
+    let slave = match slave {
+        Ok(slave) => slave,
+        Err(e) => {
+            debug!(
+                "No suitable slave found for session {}: {} (route: {:?}, allowed locations: {:?})",
+                session_id, e, route, allowed_locations
+            );
+            let reply = match e {
+                SelectError::NoSlaveAvailable => REPLY_GENERAL_FAILURE,
+                SelectError::NoSlaveInLocation(_) => REPLY_NETWORK_UNREACHABLE,
+            };
+            reply_to_client(&mut cli_stream, request.protocol, reply, None).await?;
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, e.to_string()));
+        }
     };
 
-    // Step 3: Forward destination info to the slave
-    let dest_info = format!("{}:{}", dest_address, dest_port);
-    let init_session_packet = build_init_session_command(session_id, &dest_info);
-    if timeout(CLIENT_REQUEST_TIMEOUT, slave_tx.send(init_session_packet))
-        .await
-        .is_err()
-    {
+    // UDP and BIND need slave support negotiated in the hello exchange
+    let required = match request.command {
+        Socks5Command::Connect => Capabilities::default(),
+        Socks5Command::Bind => Capabilities::BIND,
+        Socks5Command::UdpAssociate => Capabilities::UDP,
+    };
+    if !slave.supports(required) {
+        debug!(
+            "Slave selected for session {} does not support {:?}",
+            session_id, request.command
+        );
+        reply_to_client(&mut cli_stream, request.protocol, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Slave does not support {:?}", request.command),
+        ));
+    }
+
+    // Add client session so the slave's ack can be routed back
+    let mut entry = client.clone();
+    entry.slave_token = Some(slave.token);
+    entry.slave_tx = Some(slave.tx.clone());
+    proxy_manager.clients.insert(session_id, entry);
+
+    // Step 3: Forward destination info to the slave, or ask it to open a UDP
+    // socket once ours is bound
+    let dest_info = format!("{}:{}", request.address, request.port);
+    let udp_socket = match request.command {
+        Socks5Command::Connect | Socks5Command::Bind => None,
+        Socks5Command::UdpAssociate => {
+            let local_ip = cli_stream.local_addr()?.ip();
+            match UdpSocket::bind(SocketAddr::new(local_ip, 0)).await {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    proxy_manager.clients.remove(&session_id);
+                    reply_to_client(&mut cli_stream, request.protocol, REPLY_GENERAL_FAILURE, None).await?;
+                    return Err(e);
+                }
+            }
+        }
+    };
+    let init_session_packet = match request.command {
+        Socks5Command::Connect => build_init_session_command(session_id, &dest_info),
+        Socks5Command::Bind => build_bind_command(session_id, &dest_info),
+        Socks5Command::UdpAssociate => build_udp_associate_command(session_id),
+    };
+    if !slave.send(init_session_packet).await {
         debug!("Failed to send data to slave for session {}", session_id);
+        proxy_manager.clients.remove(&session_id);
+        reply_to_client(&mut cli_stream, request.protocol, REPLY_GENERAL_FAILURE, None).await?;
         return Err(std::io::Error::new(
             std::io::ErrorKind::BrokenPipe,
             "Failed to send to slave tx",
         ));
     }
 
-    // Add client session
-    proxy_manager
-        .lock()
-        .await
-        .clients
-        .insert(session_id, client.clone());
+    // Step 4: Wait for the slave's connect result before answering the client.
+    // Slaves without acks only confirm BIND and UDP, a CONNECT is assumed to work.
+    let acknowledged = request.command != Socks5Command::Connect || slave.supports(Capabilities::SESSION_ACK);
+    let ack = if !acknowledged {
+        SessionAck {
+            status: SessionStatus::Success,
+            bound_addr: None,
+        }
+    } else {
+        match timeout(CLIENT_REQUEST_TIMEOUT, client_rx.recv()).await {
+            Ok(Some(ClientEvent::SessionAck(ack))) => ack,
+            Ok(_) => SessionAck {
+                status: SessionStatus::GeneralFailure,
+                bound_addr: None,
+            },
+            Err(_) => {
+                debug!("Slave did not acknowledge session {} in time", session_id);
+                if slave.supports(Capabilities::CLOSE_FRAMES) {
+                    let close_packet = build_close_session_command(session_id);
+                    let _ = slave.tx.try_send(close_packet);
+                }
+                SessionAck {
+                    status: SessionStatus::GeneralFailure,
+                    bound_addr: None,
+                }
+            }
+        }
+    };
+
+    // Associate clients send datagrams to our relay socket, not the slave's
+    let bound_addr = match &udp_socket {
+        Some(socket) => Some(socket.local_addr()?),
+        None => ack.bound_addr,
+    };
+    reply_to_client(&mut cli_stream, request.protocol, reply_code(ack.status), bound_addr).await?;
+    if ack.status != SessionStatus::Success {
+        debug!(
+            "Slave could not connect session {} to {}: {:?}",
+            session_id, dest_info, ack.status
+        );
+        proxy_manager.clients.remove(&session_id);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("Slave failed to connect to {}", dest_info),
+        ));
+    }
+
+    // BIND answers a second time once the peer has connected to the slave
+    if request.command == Socks5Command::Bind {
+        // The client may start sending before the peer shows up; keep those
+        // bytes, up to one window, for when the session starts relaying
+        let mut early = BytesMut::new();
+        let mut client_gone = false;
+        let accept = timeout(BIND_ACCEPT_TIMEOUT, client_rx.recv());
+        tokio::pin!(accept);
+        let ack = loop {
+            tokio::select! {
+                event = &mut accept => break match event {
+                    Ok(Some(ClientEvent::SessionAck(ack))) => ack,
+                    _ => SessionAck {
+                        status: SessionStatus::GeneralFailure,
+                        bound_addr: None,
+                    },
+                },
+                read = cli_stream.read_buf(&mut early), if early.len() < INITIAL_WINDOW as usize => match read {
+                    Ok(0) | Err(_) => {
+                        // The client gave up waiting, nothing to reply to
+                        client_gone = true;
+                        break SessionAck {
+                            status: SessionStatus::GeneralFailure,
+                            bound_addr: None,
+                        };
+                    }
+                    Ok(_) => {}
+                },
+            }
+        };
+
+        let replied = if client_gone {
+            Ok(())
+        } else {
+            reply_to_client(
+                &mut cli_stream,
+                request.protocol,
+                reply_code(ack.status),
+                ack.bound_addr,
+            )
+            .await
+        };
+        if ack.status != SessionStatus::Success || client_gone || replied.is_err() {
+            debug!("No peer connected to bind session {}: {:?}", session_id, ack.status);
+            proxy_manager.clients.remove(&session_id);
+            if slave.supports(Capabilities::CLOSE_FRAMES) {
+                let close_packet = build_close_session_command(session_id);
+                let _ = slave.tx.try_send(close_packet);
+            }
+            replied?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "No inbound connection for BIND",
+            ));
+        }
+        debug!("Session {}: peer {:?} connected to bind", session_id, ack.bound_addr);
+
+        if !early.is_empty() {
+            let mut initial_data = BytesMut::from(&request.initial_data[..]);
+            initial_data.extend_from_slice(&early);
+            request.initial_data = initial_data.freeze();
+        }
+    }
+
+    if let Some(udp_socket) = udp_socket {
+        // Behind a PROXY protocol upstream the TCP peer is the balancer, the
+        // datagrams come from the client itself
+        let client_ip = client.src_addr.ip();
+        return relay_udp_association(
+            session_id,
+            &client,
+            &mut cli_stream,
+            client_ip,
+            udp_socket,
+            client_rx,
+            slave,
+            proxy_manager,
+        )
+        .await;
+    }
+
+    // With flow control the slave grants credit for data in both directions;
+    // legacy slaves get whatever the client sends
+    let flow_control = slave.supports(Capabilities::FLOW_CONTROL);
+    let mut send_window = INITIAL_WINDOW as usize;
+    let mut unacked = 0usize;
+
+    // Resumable sessions keep what they sent until the slave credits it, so it
+    // can be replayed if the slave reconnects
+    let resumable = slave.supports(RESUMABLE);
+    let mut slave = slave;
+    let mut detached = false;
+    let mut replay = ReplayBuffer::default();
+    let mut received = 0u64;
+
+    // Bytes the client sent along with its request, e.g. a forwarded HTTP request
+    if !request.initial_data.is_empty() {
+        send_window = send_window.saturating_sub(request.initial_data.len());
+        replay.push(request.initial_data.clone(), resumable);
+        let data_packet = build_data_frame(session_id, &request.initial_data);
+        if !slave.send(data_packet).await {
+            warn!("Failed to send initial data to slave for session {}", session_id);
+            detached = resumable;
+        }
+    }
 
     let shard_id = session_id as usize;
 
+    // Each direction is closed independently so a FIN on one side does not
+    // cut off data still flowing the other way
+    let mut client_eof = false;
+    let mut upstream_eof = false;
+    let mut upstream_closed = false;
+
+    // Pushed forward while attached, so a detached session gives up
+    // RESUME_GRACE after it lost its slave
+    let mut resume_deadline = Instant::now() + RESUME_GRACE;
+
     // Main loop to handle continuous traffic between client and slave
     loop {
+        if !detached {
+            resume_deadline = Instant::now() + RESUME_GRACE;
+        }
         let permit = semaphore.clone().acquire_owned().await.unwrap();
         let mut buffer = buffer_pool.get_buffer(shard_id).await;
 
         tokio::select! {
-            client_read = timeout(CLIENT_REQUEST_TIMEOUT, cli_stream.read_buf(&mut buffer)) => {
+            client_read = read_limited(&mut cli_stream, &mut buffer, flow_control.then_some(send_window)),
+                if !client_eof && !detached && (!flow_control || send_window > 0) => {
                 match client_read {
-                    Ok(Ok(len)) => {
-                        if len == 0 {
-                            trace!("Client {} closed connection", session_id);
+                    Ok(0) if !slave.supports(Capabilities::CLOSE_FRAMES) => {
+                        trace!("Client {} closed connection", session_id);
+                        break;
+                    }
+                    Ok(0) => {
+                        trace!("Client {} half-closed connection", session_id);
+                        client_eof = true;
+
+                        let half_close_packet = build_half_close_command(session_id);
+                        if !slave.send(half_close_packet).await {
+                            warn!("Failed to send half close to slave for session {}", session_id);
+                            if !resumable {
+                                break;
+                            }
+                            detached = true;
+                        } else if upstream_eof {
                             break;
                         }
-
+                    }
+                    Ok(len) => {
                         debug!("sid {}, {} bytes: CLIENT -> SLAVE", session_id, len);
 
                         let data = buffer.split().freeze();
                         let data_packet = build_data_frame(session_id, &data);
+                        if flow_control {
+                            send_window -= len;
+                        }
+                        replay.push(data, resumable);
 
-                        if timeout(CLIENT_REQUEST_TIMEOUT, slave_tx.send(data_packet)).await.is_err() {
+                        if !slave.send(data_packet).await {
                             warn!("Failed to send data to slave for session {}", session_id);
-                            break;
+                            if !resumable {
+                                break;
+                            }
+                            detached = true;
                         }
                     }
-                    Ok(Err(e)) => {
+                    Err(e) => {
                         trace!("Error reading from client session id {}: {}", session_id, e);
                         break;
                     }
-                    Err(_) => {
-                        trace!("Timeout reading from client session id {}", session_id);
-                        break;
-                    }
                 }
             }
 
             // Handle traffic from the slave to the client
-            Some(payload) = client_rx.recv() => {
-                debug!("sid {}, {} bytes: MASTER replied", session_id, payload.len());
-                if let Err(e) = cli_stream.write_all(&payload).await {
-                    error!("Failed to send data to client session id {}: {}", session_id, e);
-                    break;
-                }
-                if let Err(e) = cli_stream.flush().await {
-                    error!("Failed to flush stream for client session id {}: {}", session_id, e);
-                    break;
+            event = client_rx.recv() => {
+                match event {
+                    Some(ClientEvent::Data(payload)) => {
+                        debug!("sid {}, {} bytes: MASTER replied", session_id, payload.len());
+                        if let Err(e) = cli_stream.write_all(&payload).await {
+                            error!("Failed to send data to client session id {}: {}", session_id, e);
+                            break;
+                        }
+                        if let Err(e) = cli_stream.flush().await {
+                            error!("Failed to flush stream for client session id {}: {}", session_id, e);
+                            break;
+                        }
+                        client.pending.fetch_sub(payload.len(), Ordering::AcqRel);
+                        received += payload.len() as u64;
+
+                        // Hand the written bytes back to the slave as credit
+                        unacked += payload.len();
+                        if flow_control && unacked >= WINDOW_UPDATE_THRESHOLD {
+                            let update_packet = build_window_update_command(session_id, unacked as u32);
+                            if !slave.send(update_packet).await {
+                                warn!("Failed to send window update to slave for session {}", session_id);
+                                if !resumable {
+                                    break;
+                                }
+                                detached = true;
+                            }
+                            unacked = 0;
+                        }
+                    }
+                    Some(ClientEvent::WindowUpdate(credit)) => {
+                        trace!("Session {} granted {} bytes by slave", session_id, credit);
+                        send_window = send_window.saturating_add(credit as usize);
+                        replay.acknowledge(credit as u64);
+                    }
+                    Some(ClientEvent::Detached) => {
+                        debug!("Slave of session {} disconnected, waiting for it to resume", session_id);
+                        detached = true;
+                    }
+                    Some(ClientEvent::Resume(offset)) => {
+                        let handle = match proxy_manager.slave_handle(slave.token) {
+                            Some(handle) if resumable => handle,
+                            _ => {
+                                debug!("Session {} cannot be resumed", session_id);
+                                break;
+                            }
+                        };
+                        let Some(pending) = replay.resume_from(offset) else {
+                            warn!("Slave asked to resume session {} at unknown offset {}", session_id, offset);
+                            break;
+                        };
+
+                        // Tell the slave where to pick up, then replay what it missed
+                        slave = handle;
+                        if let Some(mut entry) = proxy_manager.clients.get_mut(&session_id) {
+                            entry.slave_tx = Some(slave.tx.clone());
+                        }
+                        let mut resumed = slave.send(build_resume_session_command(session_id, received)).await;
+                        for data in &pending {
+                            resumed = resumed && slave.send(build_data_frame(session_id, data)).await;
+                        }
+                        if client_eof {
+                            resumed = resumed && slave.send(build_half_close_command(session_id)).await;
+                        }
+                        if !resumed {
+                            warn!("Failed to resume session {}", session_id);
+                            break;
+                        }
+
+                        debug!("Session {} resumed at offset {}", session_id, offset);
+                        send_window = (INITIAL_WINDOW as usize).saturating_sub(replay.len());
+                        unacked = 0;
+                        detached = false;
+                    }
+                    Some(ClientEvent::HalfClose) => {
+                        trace!("Upstream of session {} half-closed", session_id);
+                        upstream_eof = true;
+                        if let Err(e) = cli_stream.shutdown().await {
+                            trace!("Failed to shut down client session id {}: {}", session_id, e);
+                            break;
+                        }
+                        if client_eof {
+                            break;
+                        }
+                    }
+                    Some(ClientEvent::SessionAck(_)) => {
+                        trace!("Ignoring duplicate ack for session {}", session_id);
+                    }
+                    Some(ClientEvent::Datagram(payload)) => {
+                        trace!("Ignoring datagram on TCP session {}", session_id);
+                        client.pending.fetch_sub(payload.len(), Ordering::AcqRel);
+                    }
+                    Some(ClientEvent::Reset) => {
+                        debug!("Session {} reset, client could not keep up", session_id);
+                        break;
+                    }
+                    Some(ClientEvent::Close) | None => {
+                        trace!("Upstream of session {} closed", session_id);
+                        upstream_closed = true;
+                        break;
+                    }
                 }
             }
+
+            _ = tokio::time::sleep_until(resume_deadline), if detached => {
+                debug!("Slave of session {} did not resume in time", session_id);
+                break;
+            }
+
+            _ = tokio::time::sleep(CLIENT_REQUEST_TIMEOUT) => {
+                trace!("Timeout on client session id {}", session_id);
+                break;
+            }
         }
 
         buffer_pool.return_buffer(shard_id, buffer).await;
@@ -470,7 +1568,15 @@ pub async fn handle_client_io(
     }
 
     // Cleanup after the session ends
-    proxy_manager.lock().await.clients.remove(&session_id);
+    proxy_manager.clients.remove(&session_id);
+
+    // Let the slave release the remote socket unless it closed it already
+    if !upstream_closed && !detached && slave.supports(Capabilities::CLOSE_FRAMES) {
+        let close_packet = build_close_session_command(session_id);
+        if !slave.send(close_packet).await {
+            debug!("Failed to send close session to slave for session {}", session_id);
+        }
+    }
 
     // Close the client stream
     debug!("Closing client stream for session ID {}.", session_id);
@@ -478,3 +1584,967 @@ pub async fn handle_client_io(
 
     Ok(())
 }
+
+// Data sent to the slave that it has not credited yet, starting at `offset`
+// bytes into the session
+#[derive(Default)]
+struct ReplayBuffer {
+    offset: u64,
+    frames: VecDeque<Bytes>,
+    len: usize,
+}
+
+impl ReplayBuffer {
+    // Sessions that cannot be resumed only track the offset
+    fn push(&mut self, data: Bytes, keep: bool) {
+        if keep {
+            self.len += data.len();
+            self.frames.push_back(data);
+        } else {
+            self.offset += data.len() as u64;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    // The slave consumed this many more bytes, they won't be replayed
+    fn acknowledge(&mut self, mut bytes: u64) {
+        while bytes > 0 {
+            let Some(front) = self.frames.front_mut() else {
+                break;
+            };
+            let consumed = bytes.min(front.len() as u64) as usize;
+            front.advance(consumed);
+            self.offset += consumed as u64;
+            self.len -= consumed;
+            bytes -= consumed as u64;
+            if front.is_empty() {
+                self.frames.pop_front();
+            }
+        }
+    }
+
+    // Data the slave is missing when it has received `offset` bytes, None if
+    // that offset was never sent or was already dropped
+    fn resume_from(&mut self, offset: u64) -> Option<Vec<Bytes>> {
+        let skip = offset.checked_sub(self.offset)?;
+        if skip > self.len as u64 {
+            return None;
+        }
+        self.acknowledge(skip);
+        Some(self.frames.iter().cloned().collect())
+    }
+}
+
+// Read from the client, at most `limit` bytes when the session is out of credit
+async fn read_limited(
+    cli_stream: &mut ClientStream,
+    buffer: &mut BytesMut,
+    limit: Option<usize>,
+) -> Result<usize, std::io::Error> {
+    match limit {
+        Some(limit) => cli_stream.take(limit as u64).read_buf(buffer).await,
+        None => cli_stream.read_buf(buffer).await,
+    }
+}
+
+// Answer the request phase in whichever protocol the client spoke
+async fn reply_to_client(
+    cli_stream: &mut ClientStream,
+    protocol: ClientProtocol,
+    reply: u8,
+    bound_addr: Option<SocketAddr>,
+) -> Result<(), std::io::Error> {
+    match protocol {
+        ClientProtocol::Socks4 => send_socks4_reply(cli_stream, reply).await,
+        ClientProtocol::Socks5 => send_reply(cli_stream, reply, bound_addr).await,
+        // The origin server's own response follows instead
+        ClientProtocol::HttpForward if reply == REPLY_SUCCEEDED => Ok(()),
+        ClientProtocol::HttpConnect | ClientProtocol::HttpForward => send_http_reply(cli_stream, reply).await,
+    }
+}
+
+// Relay datagrams between the client's UDP socket and the slave for as long
+// as the control connection stays open and the association is not idle
+#[allow(clippy::too_many_arguments)]
+async fn relay_udp_association(
+    session_id: u32,
+    client: &Client,
+    cli_stream: &mut ClientStream,
+    client_ip: IpAddr,
+    udp_socket: UdpSocket,
+    mut client_rx: mpsc::UnboundedReceiver<ClientEvent>,
+    slave: SlaveHandle,
+    proxy_manager: Arc<ProxyManager>,
+) -> Result<(), std::io::Error> {
+    let mut datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut control = [0u8; 64];
+    // Replies go to wherever the client last sent from
+    let mut client_udp_addr: Option<SocketAddr> = None;
+    let mut upstream_closed = false;
+
+    debug!(
+        "Session {}: UDP association relaying on {}",
+        session_id,
+        udp_socket.local_addr()?
+    );
+
+    loop {
+        tokio::select! {
+            received = udp_socket.recv_from(&mut datagram) => {
+                let (len, from) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        trace!("UDP receive error on session {}: {}", session_id, e);
+                        break;
+                    }
+                };
+
+                // Only the client owning the control connection may use the relay
+                if from.ip() != client_ip {
+                    trace!("Dropping datagram from foreign address {} on session {}", from, session_id);
+                    continue;
+                }
+                client_udp_addr = Some(from);
+
+                match strip_udp_header(&datagram[..len]) {
+                    Some(payload) => {
+                        debug!("sid {}, {} bytes: CLIENT -> SLAVE (udp)", session_id, payload.len());
+                        let frame = build_datagram_frame(session_id, payload);
+                        if !slave.send(frame).await {
+                            warn!("Failed to send datagram to slave for session {}", session_id);
+                            break;
+                        }
+                    }
+                    None => trace!("Dropping malformed or fragmented datagram on session {}", session_id),
+                }
+            }
+
+            event = client_rx.recv() => {
+                match event {
+                    Some(ClientEvent::Datagram(payload)) => {
+                        client.pending.fetch_sub(payload.len(), Ordering::AcqRel);
+                        let Some(addr) = client_udp_addr else {
+                            trace!("No client UDP address yet for session {}, dropping reply", session_id);
+                            continue;
+                        };
+                        let mut reply = BytesMut::with_capacity(3 + payload.len());
+                        reply.extend_from_slice(&[0x00, 0x00, 0x00]);
+                        reply.extend_from_slice(&payload);
+                        if let Err(e) = udp_socket.send_to(&reply, addr).await {
+                            trace!("Failed to send datagram to client session id {}: {}", session_id, e);
+                        }
+                    }
+                    Some(ClientEvent::Close) | None => {
+                        upstream_closed = true;
+                        break;
+                    }
+                    Some(ClientEvent::Reset) | Some(ClientEvent::Detached) => break,
+                    Some(_) => {}
+                }
+            }
+
+            // The association ends when the control connection goes away
+            read = cli_stream.read(&mut control) => {
+                match read {
+                    Ok(0) | Err(_) => {
+                        trace!("Control connection of UDP session {} closed", session_id);
+                        break;
+                    }
+                    Ok(_) => {}
+                }
+            }
+
+            _ = tokio::time::sleep(UDP_ASSOCIATION_IDLE_TIMEOUT) => {
+                trace!("UDP association {} idle, closing", session_id);
+                break;
+            }
+        }
+    }
+
+    proxy_manager.clients.remove(&session_id);
+    if !upstream_closed && slave.supports(Capabilities::CLOSE_FRAMES) {
+        let close_packet = build_close_session_command(session_id);
+        if !slave.send(close_packet).await {
+            debug!("Failed to send close session to slave for session {}", session_id);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthPolicy;
+    use crate::packet::{CommandType, Frame, PacketType, MASTER_HELLO};
+    use crate::utils::get_socket_addr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn test_slave(location: &str) -> (Slave, mpsc::Receiver<Bytes>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut slave, rx) = Slave::new("127.0.0.1".to_string(), stream);
+        slave.set_location(location.to_string());
+        slave.set_speed(10.0);
+        (slave, rx)
+    }
+
+    async fn test_client() -> (Client, mpsc::UnboundedReceiver<ClientEvent>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let src_addr = stream.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Client::new(Arc::new(AsyncMutex::new(stream.into())), src_addr, tx), rx)
+    }
+
+    const SESSION_ID: u32 = 7;
+
+    // A client session run by handle_client_io against one slave, whose side
+    // of the link the test plays through `slave_rx` and route_to_client
+    struct TestSession {
+        client: TcpStream,
+        slave_rx: mpsc::Receiver<Bytes>,
+        manager: Arc<ProxyManager>,
+        task: tokio::task::JoinHandle<Result<(), std::io::Error>>,
+    }
+
+    impl TestSession {
+        async fn start(capabilities: Capabilities) -> Self {
+            let manager = Arc::new(ProxyManager::new(2, Duration::from_secs(60)));
+            let (mut slave, slave_rx) = test_slave("US").await;
+            slave.set_protocol(2, capabilities);
+            manager.add_slave(slave);
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (stream, src_addr) = listener.accept().await.unwrap();
+            let (tx, rx) = mpsc::unbounded_channel();
+            let session = Client::new(Arc::new(AsyncMutex::new(stream.into())), src_addr, tx);
+            let auth = Arc::new(ClientAuth::new(AuthPolicy::NoAuth, None));
+            let task = tokio::spawn(handle_client_io(
+                SESSION_ID,
+                session,
+                rx,
+                Arc::clone(&manager),
+                Arc::new(Semaphore::new(4)),
+                Arc::new(ShardedBufferPool::new(1, 4)),
+                auth,
+                Frontend::Socks,
+            ));
+            Self {
+                client,
+                slave_rx,
+                manager,
+                task,
+            }
+        }
+
+        // Greet without auth and send a request for 127.0.0.1:80
+        async fn request(&mut self, command: u8) {
+            self.client
+                .write_all(&[0x05, 0x01, 0x00, 0x05, command, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            let mut method = [0u8; 2];
+            self.client.read_exact(&mut method).await.unwrap();
+            assert_eq!(method, [0x05, 0x00]);
+        }
+
+        async fn next_frame(&mut self) -> Frame {
+            let mut frame = BytesMut::from(&self.slave_rx.recv().await.unwrap()[..]);
+            FrameCodec::new(usize::MAX).decode(&mut frame).unwrap().unwrap()
+        }
+
+        fn ack(&self, status: SessionStatus, bound_addr: Option<SocketAddr>) {
+            self.manager
+                .route_to_client(SESSION_ID, ClientEvent::SessionAck(SessionAck { status, bound_addr }));
+        }
+
+        // Reply code and bound address of the next SOCKS5 reply, IPv4 only
+        async fn reply(&mut self) -> (u8, SocketAddr) {
+            let mut reply = [0u8; 10];
+            self.client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..4], [0x05, reply[1], 0x00, 0x01]);
+            (reply[1], get_socket_addr(&reply[3..]).unwrap().0)
+        }
+
+        // Connect the session with a successful ack
+        async fn connect(&mut self) {
+            self.request(0x01).await;
+            let frame = self.next_frame().await;
+            assert_eq!(frame.command_type, Some(CommandType::InitSession));
+            assert_eq!(&frame.payload[..], b"127.0.0.1:80");
+            self.ack(SessionStatus::Success, None);
+            assert_eq!(self.reply().await.0, REPLY_SUCCEEDED);
+        }
+    }
+
+    #[tokio::test]
+    async fn country_filter_searches_matching_slaves() {
+        let manager = ProxyManager::new(2, Duration::from_secs(60));
+        let mut receivers = Vec::new();
+        for location in ["US", "US", "US", "DE"] {
+            let (slave, rx) = test_slave(location).await;
+            manager.add_slave(slave);
+            receivers.push(rx);
+        }
+
+        let client_addr = SocketAddr::from(([10, 0, 0, 1], 40000));
+        let route = RouteRequest {
+            country: Some("de".to_string()),
+            ..Default::default()
+        };
+        for _ in 0..8 {
+            let handle = manager.get_available_slave(&client_addr, None, &route, &[]).unwrap();
+            assert!(handle.tx.same_channel(&manager.slaves.get("3").unwrap().tx));
+        }
+
+        let route = RouteRequest {
+            country: Some("FR".to_string()),
+            ..Default::default()
+        };
+        let err = manager
+            .get_available_slave(&client_addr, None, &route, &[])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no slave in FR");
+    }
+
+    #[tokio::test]
+    async fn ip_hash_spreads_distinct_clients() {
+        let manager = ProxyManager::new(1, Duration::from_secs(60));
+        let mut receivers = Vec::new();
+        for _ in 0..4 {
+            let (slave, rx) = test_slave("US").await;
+            manager.add_slave(slave);
+            receivers.push(rx);
+        }
+
+        let route = RouteRequest::default();
+        let mut hits = HashSet::new();
+        for i in 0..64u8 {
+            let client_addr = SocketAddr::from(([203, 0, 113, i], 50000));
+            let tx = manager.get_available_slave(&client_addr, None, &route, &[]).unwrap().tx;
+
+            let token = manager
+                .slaves
+                .iter()
+                .find(|entry| entry.value().tx.same_channel(&tx))
+                .map(|entry| entry.value().id_token)
+                .unwrap();
+            hits.insert(token);
+
+            // The same client keeps landing on the same slave
+            let again = manager.get_available_slave(&client_addr, None, &route, &[]).unwrap();
+            assert!(again.tx.same_channel(&tx));
+        }
+
+        assert!(hits.len() > 1, "all clients hashed to slave {:?}", hits);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn named_sessions_stick_until_they_expire() {
+        let manager = ProxyManager::new(2, Duration::from_secs(60));
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            let (slave, rx) = test_slave("US").await;
+            manager.add_slave(slave);
+            receivers.push(rx);
+        }
+
+        // Round robin would rotate, the named session stays put from any address
+        let route = RouteRequest {
+            session: Some("abc".to_string()),
+            sticky_ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let pinned = manager
+            .get_available_slave(&SocketAddr::from(([10, 0, 0, 1], 40000)), Some("alice"), &route, &[])
+            .unwrap()
+            .token;
+        for i in 2..8u8 {
+            let client_addr = SocketAddr::from(([10, 0, 0, i], 40000));
+            let handle = manager
+                .get_available_slave(&client_addr, Some("alice"), &route, &[])
+                .unwrap();
+            assert_eq!(handle.token, pinned);
+        }
+
+        // Another user's session of the same name is pinned separately
+        manager
+            .get_available_slave(&SocketAddr::from(([10, 0, 0, 1], 40000)), Some("bob"), &route, &[])
+            .unwrap();
+        assert_eq!(manager.affinities.get("alice:abc").unwrap().token, pinned);
+        assert!(manager.affinities.contains_key("bob:abc"));
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        let live = manager.purge_expired_affinities();
+        assert_eq!(live, vec![Duration::from_secs(6); 2]);
+
+        // Once expired the pin is swept and the next connect pins afresh
+        tokio::time::advance(Duration::from_secs(7)).await;
+        assert!(manager.purge_expired_affinities().is_empty());
+        assert!(manager.affinities.is_empty());
+        let token = manager
+            .get_available_slave(&SocketAddr::from(([10, 0, 0, 1], 40000)), Some("alice"), &route, &[])
+            .unwrap()
+            .token;
+        let affinity = *manager.affinities.get("alice:abc").unwrap();
+        assert_eq!(affinity.token, token);
+        assert_eq!(affinity.expires_at, Instant::now() + Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn pin_is_dropped_with_its_slave() {
+        let manager = ProxyManager::new(1, Duration::from_secs(60));
+        let (slave, _rx) = test_slave("US").await;
+        let slave = manager.add_slave(slave);
+        let (other, _other_rx) = test_slave("US").await;
+        let other = manager.add_slave(other);
+
+        let route = RouteRequest {
+            session: Some("abc".to_string()),
+            ..Default::default()
+        };
+        let client_addr = SocketAddr::from(([10, 0, 0, 1], 40000));
+        let pinned = manager
+            .get_available_slave(&client_addr, None, &route, &[])
+            .unwrap()
+            .token;
+        let (gone, kept) = match pinned == slave.id_token {
+            true => (&slave, &other),
+            false => (&other, &slave),
+        };
+
+        manager.remove_slave(gone);
+        assert!(manager.affinities.get("abc").is_none());
+        let handle = manager.get_available_slave(&client_addr, None, &route, &[]).unwrap();
+        assert_eq!(handle.token, kept.id_token);
+        assert_eq!(manager.affinities.get("abc").unwrap().token, kept.id_token);
+    }
+
+    #[tokio::test]
+    async fn slave_connections_share_one_identity() {
+        let manager = ProxyManager::new(2, Duration::from_secs(60));
+        let mut receivers = Vec::new();
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            let (mut slave, rx) = test_slave("US").await;
+            slave.slave_id = Some("edge-1".to_string());
+            connections.push(manager.add_slave(slave));
+            receivers.push(rx);
+        }
+        assert_eq!(manager.slaves.len(), 1);
+
+        // Concurrent sessions are spread evenly over the connections
+        let client_addr = SocketAddr::from(([10, 0, 0, 1], 40000));
+        let route = RouteRequest::default();
+        let handles: Vec<SlaveHandle> = (0..6)
+            .map(|_| manager.get_available_slave(&client_addr, None, &route, &[]).unwrap())
+            .collect();
+        for connection in &connections {
+            let carried = handles.iter().filter(|h| h.tx.same_channel(&connection.tx)).count();
+            assert_eq!(carried, 2);
+        }
+
+        // The slave stays registered until its last connection drops
+        manager.remove_slave(&connections[0]);
+        manager.remove_slave(&connections[1]);
+        assert_eq!(manager.slaves.len(), 1);
+        let handle = manager.get_available_slave(&client_addr, None, &route, &[]).unwrap();
+        assert!(handle.tx.same_channel(&connections[2].tx));
+
+        manager.remove_slave(&connections[2]);
+        assert!(manager.slaves.is_empty());
+        assert!(manager.slave_by_id("edge-1").is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_detaches_only_its_sessions() {
+        let manager = ProxyManager::new(2, Duration::from_secs(60));
+        let mut connections = Vec::new();
+        let mut clients = Vec::new();
+        for session_id in [1, 2] {
+            let (mut slave, rx) = test_slave("US").await;
+            slave.slave_id = Some("edge-3".to_string());
+            slave.set_protocol(2, RESUMABLE);
+            let slave = manager.add_slave(slave);
+
+            let (mut client, client_rx) = test_client().await;
+            client.slave_token = Some(slave.id_token);
+            client.slave_tx = Some(slave.tx.clone());
+            connections.push((slave, rx));
+            manager.clients.insert(session_id, client);
+            clients.push(client_rx);
+        }
+
+        // The slave is still up, the session on the lost connection has to resume
+        manager.remove_slave(&connections[0].0);
+        assert_eq!(manager.slaves.len(), 1);
+        assert!(matches!(clients[0].try_recv(), Ok(ClientEvent::Detached)));
+        assert!(clients[1].try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn detached_session_gives_up_after_grace() {
+        let mut session = TestSession::start(MASTER_HELLO.capabilities).await;
+        session.connect().await;
+
+        let started = Instant::now();
+        session.manager.route_to_client(SESSION_ID, ClientEvent::Detached);
+        (&mut session.task).await.unwrap().unwrap();
+        assert!(started.elapsed() >= RESUME_GRACE);
+        assert!(started.elapsed() < CLIENT_REQUEST_TIMEOUT);
+        assert!(session.manager.clients.get(&SESSION_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn slave_reclaims_token_after_reconnect() {
+        let manager = ProxyManager::new(1, Duration::from_secs(60));
+        let (mut slave, _rx) = test_slave("US").await;
+        slave.slave_id = Some("edge-2".to_string());
+        slave.set_protocol(2, RESUMABLE);
+        let slave = manager.add_slave(slave);
+        let (other, _other_rx) = test_slave("US").await;
+        let other = manager.add_slave(other);
+
+        // A client pinned to the slave and a session it carries
+        let client_addr = SocketAddr::from(([10, 0, 0, 1], 40000));
+        manager.affinities.insert(
+            "10.0.0.1".to_string(),
+            Affinity {
+                token: slave.id_token,
+                expires_at: Instant::now() + Duration::from_secs(60),
+            },
+        );
+        let (mut client, mut client_rx) = test_client().await;
+        client.slave_token = Some(slave.id_token);
+        manager.clients.insert(7, client);
+
+        manager.remove_slave(&slave);
+        assert!(matches!(client_rx.try_recv(), Ok(ClientEvent::Detached)));
+
+        // Meanwhile the pinned client is served elsewhere but keeps its pin
+        let route = RouteRequest::default();
+        let handle = manager.get_available_slave(&client_addr, None, &route, &[]).unwrap();
+        assert_eq!(handle.token, other.id_token);
+        assert_eq!(manager.affinities.get("10.0.0.1").unwrap().token, slave.id_token);
+
+        // Claiming the ID isn't enough to take the token over
+        let (mut impostor, _impostor_rx) = test_slave("US").await;
+        impostor.slave_id = Some("edge-2".to_string());
+        let impostor = manager.add_slave(impostor);
+        assert_ne!(impostor.id_token, slave.id_token);
+        assert!(impostor.slave_id.is_none());
+
+        let (mut again, _again_rx) = test_slave("US").await;
+        again.slave_id = Some("edge-2".to_string());
+        again.id_verified = true;
+        let again = manager.add_slave(again);
+        assert_eq!(again.id_token, slave.id_token);
+        let handle = manager.get_available_slave(&client_addr, None, &route, &[]).unwrap();
+        assert_eq!(handle.token, slave.id_token);
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_the_other_direction_open() {
+        let mut session = TestSession::start(MASTER_HELLO.capabilities).await;
+        session.connect().await;
+
+        // The client's FIN reaches the slave as HalfClose
+        session.client.shutdown().await.unwrap();
+        let frame = session.next_frame().await;
+        assert_eq!(frame.command_type, Some(CommandType::HalfClose));
+        assert_eq!(frame.session_id, SESSION_ID);
+
+        // Upstream data still flows until the slave half-closes as well
+        session
+            .manager
+            .route_to_client(SESSION_ID, ClientEvent::Data(Bytes::from_static(b"response")));
+        session.manager.route_to_client(SESSION_ID, ClientEvent::HalfClose);
+        let mut received = Vec::new();
+        session.client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"response");
+
+        (&mut session.task).await.unwrap().unwrap();
+        let frame = session.next_frame().await;
+        assert_eq!(frame.command_type, Some(CommandType::CloseSession));
+        assert!(session.manager.clients.get(&SESSION_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn close_from_slave_ends_the_session() {
+        let mut session = TestSession::start(MASTER_HELLO.capabilities).await;
+        session.connect().await;
+
+        session.manager.route_to_client(SESSION_ID, ClientEvent::Close);
+        let mut received = Vec::new();
+        session.client.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+
+        // The slave closed it, so it isn't told again
+        (&mut session.task).await.unwrap().unwrap();
+        assert!(session.slave_rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unacknowledged_session_is_a_general_failure() {
+        let mut session = TestSession::start(MASTER_HELLO.capabilities).await;
+        session.request(0x01).await;
+        let frame = session.next_frame().await;
+        assert_eq!(frame.command_type, Some(CommandType::InitSession));
+
+        // No ack within CLIENT_REQUEST_TIMEOUT, the slave is told to drop it
+        assert_eq!(session.reply().await.0, REPLY_GENERAL_FAILURE);
+        let frame = session.next_frame().await;
+        assert_eq!(frame.command_type, Some(CommandType::CloseSession));
+        assert!((&mut session.task).await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_without_session_acks_succeeds_at_once() {
+        let mut session = TestSession::start(Capabilities::default()).await;
+        session.request(0x01).await;
+        let frame = session.next_frame().await;
+        assert_eq!(frame.command_type, Some(CommandType::InitSession));
+        assert_eq!(session.reply().await.0, REPLY_SUCCEEDED);
+
+        session.client.write_all(b"ping").await.unwrap();
+        let frame = session.next_frame().await;
+        assert_eq!(&frame.payload[..], b"ping");
+    }
+
+    #[tokio::test]
+    async fn bind_replies_twice() {
+        let mut session = TestSession::start(MASTER_HELLO.capabilities).await;
+        session.request(0x02).await;
+        let frame = session.next_frame().await;
+        assert_eq!(frame.command_type, Some(CommandType::Bind));
+
+        // First reply carries the address the slave listens on
+        let listening = SocketAddr::from(([192, 0, 2, 1], 4000));
+        session.ack(SessionStatus::Success, Some(listening));
+        assert_eq!(session.reply().await, (REPLY_SUCCEEDED, listening));
+
+        // Bytes sent while waiting for the peer are relayed once it connects
+        session.client.write_all(b"early").await.unwrap();
+        let peer = SocketAddr::from(([198, 51, 100, 7], 5555));
+        session.ack(SessionStatus::Success, Some(peer));
+        assert_eq!(session.reply().await, (REPLY_SUCCEEDED, peer));
+        let frame = session.next_frame().await;
+        assert_eq!(frame.packet_type, PacketType::Data);
+        assert_eq!(&frame.payload[..], b"early");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bind_without_peer_times_out() {
+        let mut session = TestSession::start(MASTER_HELLO.capabilities).await;
+        session.request(0x02).await;
+        session.next_frame().await;
+        let listening = SocketAddr::from(([192, 0, 2, 1], 4000));
+        session.ack(SessionStatus::Success, Some(listening));
+        assert_eq!(session.reply().await, (REPLY_SUCCEEDED, listening));
+
+        // Nobody connects within BIND_ACCEPT_TIMEOUT
+        let started = Instant::now();
+        assert_eq!(session.reply().await.0, REPLY_GENERAL_FAILURE);
+        assert!(started.elapsed() >= BIND_ACCEPT_TIMEOUT);
+        let frame = session.next_frame().await;
+        assert_eq!(frame.command_type, Some(CommandType::CloseSession));
+        assert!((&mut session.task).await.unwrap().is_err());
+        assert!(session.manager.clients.get(&SESSION_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn closed_slave_channel_fails_the_request() {
+        let mut session = TestSession::start(MASTER_HELLO.capabilities).await;
+        session.slave_rx.close();
+
+        session.request(0x01).await;
+        assert_eq!(session.reply().await.0, REPLY_GENERAL_FAILURE);
+        assert!((&mut session.task).await.unwrap().is_err());
+        assert!(session.manager.clients.get(&SESSION_ID).is_none());
+    }
+
+    // An associate session relaying through `relay`, with the TCP control
+    // connection it belongs to and the slave's end of the link
+    struct TestAssociation {
+        control: TcpStream,
+        relay: SocketAddr,
+        slave_rx: mpsc::Receiver<Bytes>,
+        manager: Arc<ProxyManager>,
+        task: tokio::task::JoinHandle<Result<(), std::io::Error>>,
+    }
+
+    impl TestAssociation {
+        async fn start() -> Self {
+            Self::start_from("127.0.0.1".parse().unwrap()).await
+        }
+
+        // The client's address as the listener saw it, possibly forwarded by
+        // PROXY protocol; the control connection always comes from 127.0.0.1
+        async fn start_from(src_ip: IpAddr) -> Self {
+            let manager = Arc::new(ProxyManager::new(2, Duration::from_secs(60)));
+            let (mut client, client_rx) = test_client().await;
+            client.src_addr.set_ip(src_ip);
+            manager.clients.insert(SESSION_ID, client.clone());
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let control = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let mut cli_stream = ClientStream::from(listener.accept().await.unwrap().0);
+            let udp_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let relay = udp_socket.local_addr().unwrap();
+
+            let (tx, slave_rx) = mpsc::channel(16);
+            let slave = SlaveHandle {
+                token: 0,
+                tx,
+                capabilities: MASTER_HELLO.capabilities,
+                _load: None,
+            };
+            let task = tokio::spawn({
+                let manager = Arc::clone(&manager);
+                async move {
+                    relay_udp_association(
+                        SESSION_ID,
+                        &client,
+                        &mut cli_stream,
+                        client.src_addr.ip(),
+                        udp_socket,
+                        client_rx,
+                        slave,
+                        manager,
+                    )
+                    .await
+                }
+            });
+            Self {
+                control,
+                relay,
+                slave_rx,
+                manager,
+                task,
+            }
+        }
+
+        async fn next_frame(&mut self) -> Frame {
+            let mut frame = BytesMut::from(&self.slave_rx.recv().await.unwrap()[..]);
+            FrameCodec::new(usize::MAX).decode(&mut frame).unwrap().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_association_relays_datagrams() {
+        let mut association = TestAssociation::start().await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let foreign = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+
+        // Only the valid datagram from the control connection's host goes through
+        let query = b"\x00\x00\x00\x01\x08\x08\x08\x08\x00\x35query";
+        foreign.send_to(query, association.relay).await.unwrap();
+        socket
+            .send_to(b"\x00\x00\x01\x01\x08\x08\x08\x08\x00\x35frag", association.relay)
+            .await
+            .unwrap();
+        socket
+            .send_to(b"\x00\x00\x00\x01\x08", association.relay)
+            .await
+            .unwrap();
+        socket.send_to(query, association.relay).await.unwrap();
+        let frame = association.next_frame().await;
+        assert_eq!(frame.packet_type, PacketType::Datagram);
+        assert_eq!(frame.session_id, SESSION_ID);
+        assert_eq!(&frame.payload[..], &query[3..]);
+
+        // Replies go back to the client with the SOCKS5 UDP header
+        let answer = Bytes::from_static(b"\x01\x08\x08\x08\x08\x00\x35answer");
+        association
+            .manager
+            .route_to_client(SESSION_ID, ClientEvent::Datagram(answer.clone()));
+        let mut reply = [0u8; 64];
+        let (len, from) = socket.recv_from(&mut reply).await.unwrap();
+        assert_eq!(from, association.relay);
+        assert_eq!(reply[..3], [0x00, 0x00, 0x00]);
+        assert_eq!(&reply[3..len], &answer[..]);
+
+        // Closing the control connection ends the association
+        drop(association.control);
+        association.task.await.unwrap().unwrap();
+        let mut frame = BytesMut::from(&association.slave_rx.recv().await.unwrap()[..]);
+        let frame = FrameCodec::new(usize::MAX).decode(&mut frame).unwrap().unwrap();
+        assert_eq!(frame.command_type, Some(CommandType::CloseSession));
+        assert!(association.manager.clients.get(&SESSION_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn udp_association_accepts_the_forwarded_address() {
+        let mut association = TestAssociation::start_from("127.0.0.2".parse().unwrap()).await;
+        let balancer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+
+        // The control connection's peer is the balancer, its datagrams are refused
+        balancer
+            .send_to(b"\x00\x00\x00\x01\x08\x08\x08\x08\x00\x35balancer", association.relay)
+            .await
+            .unwrap();
+        client
+            .send_to(b"\x00\x00\x00\x01\x08\x08\x08\x08\x00\x35client", association.relay)
+            .await
+            .unwrap();
+        let frame = association.next_frame().await;
+        assert_eq!(&frame.payload[..], b"\x01\x08\x08\x08\x08\x00\x35client");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_udp_association_closes() {
+        let mut association = TestAssociation::start().await;
+        let started = Instant::now();
+
+        (&mut association.task).await.unwrap().unwrap();
+        assert!(started.elapsed() >= UDP_ASSOCIATION_IDLE_TIMEOUT);
+        let frame = association.next_frame().await;
+        assert_eq!(frame.command_type, Some(CommandType::CloseSession));
+        assert!(association.manager.clients.get(&SESSION_ID).is_none());
+    }
+
+    #[tokio::test]
+    async fn frames_behind_the_handshake_are_processed() {
+        let manager = Arc::new(ProxyManager::new(2, Duration::from_secs(60)));
+        let (slave, slave_rx) = test_slave("US").await;
+        let slave = manager.add_slave(slave);
+        let (client, mut client_rx) = test_client().await;
+        manager.clients.insert(SESSION_ID, client);
+
+        // The slave's stream is gone, only the handshake leftovers get read
+        let leftover = BytesMut::from(&build_data_frame(SESSION_ID, b"hello")[..]);
+        handle_slave_io(
+            slave,
+            leftover,
+            slave_rx,
+            Arc::clone(&manager),
+            Arc::new(ShardedBufferPool::new(1, 4)),
+            Arc::new(Metrics::new()),
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+        match client_rx.recv().await {
+            Some(ClientEvent::Data(payload)) => assert_eq!(&payload[..], b"hello"),
+            _ => panic!("expected the buffered data frame"),
+        }
+    }
+
+    #[tokio::test]
+    async fn grouped_connections_count_as_one_slave() {
+        let manager = Arc::new(ProxyManager::new(2, Duration::from_secs(60)));
+        let metrics = Arc::new(Metrics::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peers = Vec::new();
+        let mut tasks = Vec::new();
+        for _ in 0..2 {
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            peers.push(listener.accept().await.unwrap().0);
+            let (mut slave, slave_rx) = Slave::new("127.0.0.1".to_string(), stream);
+            slave.slave_id = Some("edge-4".to_string());
+            slave.set_version("1.2.0".to_string());
+            let slave = manager.add_slave(slave);
+            tasks.push(tokio::spawn(handle_slave_io(
+                slave,
+                BytesMut::new(),
+                slave_rx,
+                Arc::clone(&manager),
+                Arc::new(ShardedBufferPool::new(1, 4)),
+                Arc::clone(&metrics),
+                usize::MAX,
+            )));
+        }
+
+        let by_version = metrics.slaves_by_version.with_label_values(&["1.2.0"]);
+        while metrics.slave_active_connections.get() < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(metrics.slaves_connected.get(), 1);
+        assert_eq!(by_version.get(), 1);
+
+        // The slave stays counted until its last connection is gone
+        drop(peers.remove(0));
+        tasks.remove(0).await.unwrap().unwrap();
+        assert_eq!(metrics.slave_active_connections.get(), 1);
+        assert_eq!(metrics.slaves_connected.get(), 1);
+        assert_eq!(by_version.get(), 1);
+
+        drop(peers);
+        tasks.remove(0).await.unwrap().unwrap();
+        assert_eq!(metrics.slaves_connected.get(), 0);
+        assert_eq!(by_version.get(), 0);
+    }
+
+    #[test]
+    fn replay_buffer_resumes_from_slave_offset() {
+        let mut replay = ReplayBuffer::default();
+        replay.push(Bytes::from_static(b"hello "), true);
+        replay.push(Bytes::from_static(b"world"), true);
+        replay.acknowledge(3);
+        assert_eq!(replay.len(), 8);
+
+        assert!(replay.resume_from(2).is_none());
+        assert!(replay.resume_from(12).is_none());
+        assert_eq!(replay.resume_from(7).unwrap().concat(), b"orld");
+        assert_eq!(replay.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn batched_frames_survive_partial_writes() {
+        let (mut writer, mut reader) = tokio::io::duplex(7);
+        let mut frames = vec![
+            Bytes::from_static(b"first frame"),
+            Bytes::new(),
+            Bytes::from_static(b"second"),
+            Bytes::from_static(b"third and last"),
+        ];
+        let expected: Vec<u8> = frames.concat();
+
+        let read = tokio::spawn(async move {
+            let mut received = vec![0u8; expected.len()];
+            reader.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+        });
+        write_frames(&mut writer, &mut frames).await.unwrap();
+        read.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_client_does_not_block_neighbours() {
+        let manager = ProxyManager::new(1, Duration::from_secs(60));
+        let (stalled, mut stalled_rx) = test_client().await;
+        let (active, mut active_rx) = test_client().await;
+        manager.clients.insert(1, stalled);
+        manager.clients.insert(2, active);
+
+        // Session 1 never reads while session 2 drains as it goes
+        let chunk = Bytes::from(vec![0u8; 64 * 1024]);
+        let rounds = MAX_PENDING_BYTES / chunk.len() + 1;
+        let mut delivered = 0;
+        for _ in 0..rounds {
+            manager.route_to_client(1, ClientEvent::Data(chunk.clone()));
+            manager.route_to_client(2, ClientEvent::Data(chunk.clone()));
+            while let Ok(ClientEvent::Data(payload)) = active_rx.try_recv() {
+                delivered += payload.len();
+                let active = manager.clients.get(&2).unwrap();
+                active.pending.fetch_sub(payload.len(), Ordering::AcqRel);
+            }
+        }
+        assert_eq!(delivered, rounds * chunk.len());
+
+        // The stalled session is dropped and told so once it falls too far behind
+        assert!(manager.clients.get(&1).is_none());
+        assert!(manager.clients.get(&2).is_some());
+        let mut reset = false;
+        while let Ok(event) = stalled_rx.try_recv() {
+            reset |= matches!(event, ClientEvent::Reset);
+        }
+        assert!(reset);
+    }
+}
@@ -0,0 +1,178 @@
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Upstream load balancers allowed to prepend a PROXY protocol header
+pub struct ProxyProtocolConfig {
+    pub trusted: Vec<IpNet>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(ip))
+    }
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Consume a PROXY protocol v1 or v2 header from the stream, returning the
+// original client address. None means the header carried no address
+// (LOCAL / UNKNOWN) and the peer address should be used.
+pub async fn read_proxy_header(stream: &mut TcpStream) -> std::io::Result<Option<SocketAddr>> {
+    timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "PROXY header timed out"))?
+}
+
+async fn read_header(stream: &mut TcpStream) -> std::io::Result<Option<SocketAddr>> {
+    // Both versions are at least 12 bytes long, enough to tell them apart
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix.starts_with(V1_PREFIX) {
+        // v1 is a single text line, read it byte by byte up to the CRLF
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else if prefix == *V2_SIGNATURE {
+        let mut header = [0u8; V2_HEADER_LEN];
+        header[..12].copy_from_slice(&prefix);
+        stream.read_exact(&mut header[12..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        parse_v2(&header, &body)
+    } else {
+        Err(invalid("Missing PROXY protocol header"))
+    }
+}
+
+// "PROXY TCP4 <src> <dst> <sport> <dport>\r\n" or "PROXY UNKNOWN ...\r\n"
+pub fn parse_v1(line: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("Malformed PROXY v1 header"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("Invalid PROXY v1 source address"))?;
+            if (*proto == "TCP4") != ip.is_ipv4() {
+                return Err(invalid("PROXY v1 address family mismatch"));
+            }
+            let port: u16 = sport.parse().map_err(|_| invalid("Invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Malformed PROXY v1 header")),
+    }
+}
+
+pub fn parse_v2(header: &[u8; V2_HEADER_LEN], body: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    if header[..12] != *V2_SIGNATURE || header[12] >> 4 != 0x2 {
+        return Err(invalid("Invalid PROXY v2 signature or version"));
+    }
+
+    match header[12] & 0x0f {
+        // LOCAL: health checks from the balancer itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("Unknown PROXY v2 command")),
+    }
+
+    match header[13] >> 4 {
+        // AF_INET: src(4) dst(4) sport(2) dport(2)
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6: src(16) dst(16) sport(2) dport(2)
+        0x2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        // AF_UNSPEC or AF_UNIX carry nothing we can balance on
+        0x0 | 0x3 => Ok(None),
+        _ => Err(invalid("Truncated PROXY v2 address block")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn v2_header(command: u8, family: u8, len: u16) -> [u8; V2_HEADER_LEN] {
+        let mut header = [0u8; V2_HEADER_LEN];
+        header[..12].copy_from_slice(V2_SIGNATURE);
+        header[12] = 0x20 | command;
+        header[13] = family;
+        header[14..].copy_from_slice(&len.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn v1_addresses() {
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 1080\r\n").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 1080\r\n").unwrap(),
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+    }
+
+    #[test]
+    fn v2_addresses() {
+        let body = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x04, 0x38];
+        assert_eq!(
+            parse_v2(&v2_header(0x1, 0x11, 12), &body).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(parse_v2(&v2_header(0x0, 0x00, 0), &[]).unwrap(), None);
+        assert!(parse_v2(&v2_header(0x1, 0x21, 12), &body).is_err());
+    }
+
+    #[tokio::test]
+    async fn header_is_consumed_and_payload_left_intact() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let mut frame = v2_header(0x1, 0x11, 12).to_vec();
+        frame.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x04, 0x38]);
+        frame.extend_from_slice(&[0x05, 0x01, 0x00]);
+        client.write_all(&frame).await.unwrap();
+
+        let addr = read_proxy_header(&mut server).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut greeting = [0u8; 3];
+        server.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [0x05, 0x01, 0x00]);
+    }
+}
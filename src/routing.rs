@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+// Routing hints encoded in the SOCKS5 username, e.g.
+//   user-country-de-city-munich-session-abc123-ttl-10m
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteRequest {
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    // Key pinning every session that carries it to the same slave
+    pub session: Option<String>,
+    pub sticky_ttl: Option<Duration>,
+}
+
+// Longest sticky TTL a client may ask for
+pub const MAX_STICKY_TTL: Duration = Duration::from_secs(24 * 3600);
+
+const KEYS: &[&str] = &["country", "region", "city", "asn", "session", "ttl"];
+
+impl RouteRequest {
+    // Check a slave's location and network against every requested field
+    pub fn matches_location(
+        &self,
+        country: Option<&str>,
+        region: Option<&str>,
+        city: Option<&str>,
+        asn: Option<u32>,
+    ) -> bool {
+        fn field_matches(requested: &Option<String>, actual: Option<&str>) -> bool {
+            match requested {
+                Some(requested) => actual.is_some_and(|actual| location_eq(requested, actual)),
+                None => true,
+            }
+        }
+
+        field_matches(&self.country, country)
+            && field_matches(&self.region, region)
+            && field_matches(&self.city, city)
+            && self.asn.is_none_or(|requested| asn == Some(requested))
+    }
+}
+
+// Compare location names ignoring case, with '_' standing in for spaces
+pub fn location_eq(a: &str, b: &str) -> bool {
+    let normalize = |c: char| if c == '_' { ' ' } else { c.to_ascii_lowercase() };
+    a.chars().map(normalize).eq(b.chars().map(normalize))
+}
+
+// Split a raw SOCKS5 username into the account name and its routing hints.
+// Everything before the first recognised key is the account name, so
+// account names may themselves contain '-'.
+pub fn parse_username(raw: &str) -> Result<(String, RouteRequest), String> {
+    let parts: Vec<&str> = raw.split('-').collect();
+    let start = parts
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(_, part)| KEYS.contains(&part.to_ascii_lowercase().as_str()))
+        .map(|(idx, _)| idx)
+        .unwrap_or(parts.len());
+
+    let username = parts[..start].join("-");
+    if username.is_empty() {
+        return Err("Empty username".to_string());
+    }
+
+    let mut route = RouteRequest::default();
+    for pair in parts[start..].chunks(2) {
+        let (key, value) = match pair {
+            [key, value] if !value.is_empty() => (key.to_ascii_lowercase(), *value),
+            _ => return Err(format!("Missing value for routing key {}", pair[0])),
+        };
+
+        match key.as_str() {
+            "country" => route.country = Some(value.to_string()),
+            "region" => route.region = Some(value.to_string()),
+            "city" => route.city = Some(value.to_string()),
+            "asn" => route.asn = Some(parse_asn(value)?),
+            "session" => route.session = Some(value.to_string()),
+            "ttl" => route.sticky_ttl = Some(parse_ttl(value)?),
+            _ => return Err(format!("Unknown routing key {}", key)),
+        }
+    }
+
+    Ok((username, route))
+}
+
+fn parse_asn(value: &str) -> Result<u32, String> {
+    let digits = value
+        .strip_prefix("AS")
+        .or_else(|| value.strip_prefix("as"))
+        .unwrap_or(value);
+    digits.parse().map_err(|_| format!("Invalid ASN {}", value))
+}
+
+// Accepts 30s, 10m, 2h; a bare number is taken as minutes. Anything longer
+// than MAX_STICKY_TTL is rejected.
+pub fn parse_ttl(value: &str) -> Result<Duration, String> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((idx, c)) if c.is_ascii_alphabetic() => (&value[..idx], c.to_ascii_lowercase()),
+        _ => (value, 'm'),
+    };
+    let amount: u64 = digits.parse().map_err(|_| format!("Invalid sticky TTL {}", value))?;
+
+    let seconds = match unit {
+        's' => Some(amount),
+        'm' => amount.checked_mul(60),
+        'h' => amount.checked_mul(3600),
+        _ => return Err(format!("Invalid sticky TTL unit in {}", value)),
+    };
+    match seconds.map(Duration::from_secs) {
+        Some(ttl) if ttl <= MAX_STICKY_TTL => Ok(ttl),
+        _ => Err(format!(
+            "Sticky TTL {} exceeds the maximum of {}h",
+            value,
+            MAX_STICKY_TTL.as_secs() / 3600
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_username_has_no_hints() {
+        let (username, route) = parse_username("alice").unwrap();
+        assert_eq!(username, "alice");
+        assert_eq!(route, RouteRequest::default());
+    }
+
+    #[test]
+    fn parses_all_hints() {
+        let (username, route) =
+            parse_username("user-country-de-region-bavaria-city-munich-asn-AS3320-session-abc123-ttl-10m").unwrap();
+        assert_eq!(username, "user");
+        assert_eq!(route.country.as_deref(), Some("de"));
+        assert_eq!(route.region.as_deref(), Some("bavaria"));
+        assert_eq!(route.city.as_deref(), Some("munich"));
+        assert_eq!(route.asn, Some(3320));
+        assert_eq!(route.session.as_deref(), Some("abc123"));
+        assert_eq!(route.sticky_ttl, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn username_may_contain_dashes() {
+        let (username, route) = parse_username("my-team-Country-US").unwrap();
+        assert_eq!(username, "my-team");
+        assert_eq!(route.country.as_deref(), Some("US"));
+    }
+
+    #[test]
+    fn rejects_malformed_hints() {
+        assert!(parse_username("user-country").is_err());
+        assert!(parse_username("user-country-de-bogus").is_err());
+        assert!(parse_username("user-asn-abc").is_err());
+        assert!(parse_username("user-ttl-5d").is_err());
+        assert!(parse_username("-country-de").is_err());
+    }
+
+    #[test]
+    fn ttl_units() {
+        assert_eq!(parse_ttl("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_ttl("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_ttl("15"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_ttl("24h"), Ok(MAX_STICKY_TTL));
+    }
+
+    #[test]
+    fn ttl_is_bounded() {
+        assert!(parse_ttl("25h").is_err());
+        assert!(parse_ttl("86401s").is_err());
+        assert!(parse_ttl("999999999999999999h").is_err());
+        assert!(parse_ttl("18446744073709551615s").is_err());
+        assert!(parse_ttl("18446744073709551615").is_err());
+        assert!(parse_ttl("99999999999999999999999s").is_err());
+        assert!(parse_username("user-session-a-ttl-999999999999999999h").is_err());
+    }
+
+    #[test]
+    fn location_matching() {
+        let route = RouteRequest {
+            country: Some("de".to_string()),
+            city: Some("frankfurt_am_main".to_string()),
+            ..Default::default()
+        };
+        assert!(route.matches_location(Some("DE"), None, Some("Frankfurt am Main"), None));
+        assert!(!route.matches_location(Some("DE"), None, Some("Berlin"), None));
+        assert!(!route.matches_location(None, None, Some("Frankfurt am Main"), None));
+    }
+}
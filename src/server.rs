@@ -1,33 +1,43 @@
-use tokio::net::TcpListener;
-use tokio::sync::Semaphore;
-use tokio::sync::mpsc;
-use tokio::time;
-use std::sync::Arc;
-use tokio::sync::Mutex as AsyncMutex;
-use bytes::{BytesMut, Buf};
-use std::error::Error;
-use log::{trace, debug, info, error};
-use serde_json;
-use crate::proxy::{handle_client_io, handle_slave_io, ProxyManager, Client, Slave};
+use crate::auth::ClientAuth;
 use crate::buffer_pool::ShardedBufferPool;
+use crate::buffer_pool::MAX_BUF_SIZE;
 use crate::metrics::Metrics;
 use crate::packet::{
-    parse_header,
-    build_speed_test_command,
-    build_version_check_command,
-    build_location_check_command
+    build_auth_challenge_command, build_hello_command, build_location_check_command, build_speed_test_command,
+    build_version_check_command, parse_auth_response, parse_hello, CommandType, Frame, FrameCodec,
+    LEGACY_PROTOCOL_VERSION, MASTER_HELLO,
 };
-use crate::buffer_pool::MAX_BUF_SIZE;
+use crate::proxy::{handle_client_io, handle_slave_io, Client, Frontend, ProxyManager, Slave};
+use crate::proxy_protocol::{read_proxy_header, ProxyProtocolConfig};
+use crate::slave_auth::{SlaveAuth, NONCE_LEN};
+use crate::tls::{ClientStream, SlaveStream};
 use crate::utils::CLIENT_REQUEST_TIMEOUT;
+use crate::version_policy::{VersionPolicy, VersionPolicyStore};
+use bytes::BytesMut;
+use log::{debug, error, info, trace};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::Semaphore;
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Decoder;
 
-const ALLOWED_SLAVE_VERSIONS: &[&str] = &["1.0.9"];
+const HELLO_TIMEOUT: time::Duration = time::Duration::from_secs(3);
 
+#[allow(clippy::too_many_arguments)]
 pub async fn start_slave_listener(
     master_addr: &str,
-    proxy_manager: Arc<AsyncMutex<ProxyManager>>,
+    proxy_manager: Arc<ProxyManager>,
     slave_buffer_pool: Arc<ShardedBufferPool>,
     metrics: Arc<Metrics>,
-    allowed_locations: Arc<Vec<String>>
+    allowed_locations: Arc<Vec<String>>,
+    version_policy: Arc<VersionPolicyStore>,
+    slave_auth: Arc<SlaveAuth>,
+    slave_tls: Option<TlsAcceptor>,
+    max_frame_size: usize,
 ) {
     let slave_listener = match TcpListener::bind(&master_addr).await {
         Err(e) => {
@@ -42,6 +52,7 @@ pub async fn start_slave_listener(
         let buffer_pool_clone = Arc::clone(&slave_buffer_pool);
         let metrics_clone = Arc::clone(&metrics);
         let allowed_locations = Arc::clone(&allowed_locations);
+        let version_policy = Arc::clone(&version_policy);
         async move {
             if let Err(e) = handle_slave_connections(
                 slave_listener,
@@ -49,29 +60,40 @@ pub async fn start_slave_listener(
                 buffer_pool_clone,
                 metrics_clone,
                 allowed_locations,
-            ).await {
+                version_policy,
+                slave_auth,
+                slave_tls,
+                max_frame_size,
+            )
+            .await
+            {
                 error!("Connection handler error: {}", e);
             }
         }
     });
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn start_client_listener(
-    socks_addr: &str,
-    proxy_manager: Arc<AsyncMutex<ProxyManager>>,
+    listen_addr: &str,
+    frontend: Frontend,
+    proxy_manager: Arc<ProxyManager>,
     semaphore: Arc<Semaphore>,
-    client_buffer_pool: Arc<ShardedBufferPool>
+    client_buffer_pool: Arc<ShardedBufferPool>,
+    auth: Arc<ClientAuth>,
+    proxy_protocol: Option<Arc<ProxyProtocolConfig>>,
+    tls: Option<TlsAcceptor>,
 ) {
-    let client_listener = match TcpListener::bind(&socks_addr).await {
+    let client_listener = match TcpListener::bind(&listen_addr).await {
         Err(e) => {
-            error!("Failed to bind SOCKS5 listener: {}", e);
+            error!("Failed to bind {:?} listener on {}: {}", frontend, listen_addr, e);
             return;
         }
         Ok(p) => p,
     };
 
     loop {
-        let (client_stream, _) = match client_listener.accept().await {
+        let (client_stream, client_addr) = match client_listener.accept().await {
             Ok(stream) => stream,
             Err(e) => {
                 error!("Error accepting client connection: {}", e);
@@ -85,18 +107,55 @@ pub async fn start_client_listener(
             continue;
         }
 
-        let client_stream = Arc::new(AsyncMutex::new(client_stream));
         let session_id = rand::random::<u32>();
 
-        let (client_tx, client_rx) = mpsc::channel(100);
-        let client = Client::new(client_stream.clone(), client_tx);
-
         // Spawn a task to handle traffic between the client and the assigned slave
         let proxy_manager_clone = Arc::clone(&proxy_manager);
         let semaphore_clone = Arc::clone(&semaphore);
         let buffer_pool_clone = Arc::clone(&client_buffer_pool);
+        let auth_clone = Arc::clone(&auth);
+        let proxy_protocol_clone = proxy_protocol.clone();
+        let tls = tls.clone();
 
         tokio::spawn(async move {
+            let mut client_stream = client_stream;
+            let mut src_addr = client_addr;
+
+            // Trusted upstreams prepend the original client address
+            if let Some(proxy_protocol) = proxy_protocol_clone {
+                if proxy_protocol.is_trusted(&client_addr.ip()) {
+                    match read_proxy_header(&mut client_stream).await {
+                        Ok(Some(addr)) => src_addr = addr,
+                        Ok(None) => {}
+                        Err(e) => {
+                            debug!("Invalid PROXY protocol header from {}: {}", client_addr, e);
+                            return;
+                        }
+                    }
+                }
+            }
+            trace!("Client session {} from {} (peer {})", session_id, src_addr, client_addr);
+
+            // The PROXY header comes in the clear, TLS starts after it
+            let client_stream = match tls {
+                Some(acceptor) => match time::timeout(CLIENT_REQUEST_TIMEOUT, acceptor.accept(client_stream)).await {
+                    Ok(Ok(stream)) => ClientStream::tls(stream),
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with client {} failed: {}", src_addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with client {} timed out", src_addr);
+                        return;
+                    }
+                },
+                None => ClientStream::Plain(client_stream),
+            };
+
+            let client_stream = Arc::new(AsyncMutex::new(client_stream));
+            let (client_tx, client_rx) = mpsc::unbounded_channel();
+            let client = Client::new(client_stream, src_addr, client_tx);
+
             if let Err(e) = handle_client_io(
                 session_id,
                 client,
@@ -104,6 +163,8 @@ pub async fn start_client_listener(
                 proxy_manager_clone,
                 semaphore_clone,
                 buffer_pool_clone,
+                auth_clone,
+                frontend,
             )
             .await
             {
@@ -113,12 +174,17 @@ pub async fn start_client_listener(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_slave_connections(
     slave_listener: TcpListener,
-    proxy_manager: Arc<AsyncMutex<ProxyManager>>,
+    proxy_manager: Arc<ProxyManager>,
     buffer_pool: Arc<ShardedBufferPool>,
     metrics: Arc<Metrics>,
     allowed_locations: Arc<Vec<String>>,
+    version_policy: Arc<VersionPolicyStore>,
+    slave_auth: Arc<SlaveAuth>,
+    slave_tls: Option<TlsAcceptor>,
+    max_frame_size: usize,
 ) -> Result<(), Box<dyn Error>> {
     loop {
         let (slave_stream, slave_addr) = match slave_listener.accept().await {
@@ -135,36 +201,79 @@ pub async fn handle_slave_connections(
             continue;
         }
 
-        trace!("New Slave attempting to connect: {}:{}", slave_addr.ip(), slave_addr.port());
+        if slave_auth.is_blocked(slave_addr.ip()) {
+            debug!("Refusing slave {}, too many failed authentications", slave_addr.ip());
+            continue;
+        }
 
-        // Create a new Slave object
-        let (new_slave, slave_rx) = Slave::new(slave_addr.ip().to_string(), slave_stream);
+        trace!(
+            "New Slave attempting to connect: {}:{}",
+            slave_addr.ip(),
+            slave_addr.port()
+        );
 
         // Spawn a task to handle the slave's I/O operations
         let proxy_manager_clone = Arc::clone(&proxy_manager);
         let allowed_locations_clone = Arc::clone(&allowed_locations);
         let buffer_pool_clone = Arc::clone(&buffer_pool);
         let metrics_clone = Arc::clone(&metrics);
+        let version_policy = version_policy.current();
+        let slave_auth = Arc::clone(&slave_auth);
+        let slave_tls = slave_tls.clone();
 
         tokio::spawn(async move {
+            // Handshake inside the task so a slow peer can't hold up accepting
+            let slave_stream = match slave_tls {
+                Some(acceptor) => match time::timeout(CLIENT_REQUEST_TIMEOUT, acceptor.accept(slave_stream)).await {
+                    Ok(Ok(stream)) => SlaveStream::Tls(Box::new(stream)),
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with slave {} failed: {}", slave_addr.ip(), e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with slave {} timed out", slave_addr.ip());
+                        return;
+                    }
+                },
+                None => SlaveStream::Plain(slave_stream),
+            };
+            let peer_identity = slave_stream.peer_identity();
+
+            // Create a new Slave object
+            let (new_slave, slave_rx) = Slave::new(slave_addr.ip().to_string(), slave_stream);
             let mut slave = new_slave.clone();
-            
+            // A client certificate pins the ID the slave may register as
+            slave.id_verified = peer_identity.is_some();
+            slave.slave_id = peer_identity;
+
             // Perform validation
-            match verify_slave_session(&mut slave, &allowed_locations_clone).await {
-                Ok(_) => {
+            let mut codec = FrameCodec::new(max_frame_size);
+            match verify_slave_session(
+                &mut slave,
+                &proxy_manager_clone,
+                &allowed_locations_clone,
+                &version_policy,
+                &slave_auth,
+                &mut codec,
+            )
+            .await
+            {
+                Ok(leftover) => {
                     debug!("Slave {} validation passed.", slave.ip_addr);
 
                     // Add the validated slave to the proxy manager
-                    proxy_manager_clone.lock().await.add_slave(slave.clone()).await;
+                    let slave = proxy_manager_clone.add_slave(slave);
                     info!("Slave {} successfully registered.", new_slave.ip_addr);
 
                     // Spawn a task to handle I/O for the validated slave
                     if let Err(e) = handle_slave_io(
                         slave,
+                        leftover,
                         slave_rx,
                         proxy_manager_clone,
                         buffer_pool_clone,
                         metrics_clone,
+                        max_frame_size,
                     )
                     .await
                     {
@@ -179,64 +288,214 @@ pub async fn handle_slave_connections(
     }
 }
 
+// Read until the slave's next complete frame has arrived
+async fn read_frame(
+    slave: &Slave,
+    buffer: &mut BytesMut,
+    codec: &mut FrameCodec,
+) -> Result<Frame, Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        if let Some(frame) = codec.decode(buffer)? {
+            return Ok(frame);
+        }
+        if slave.read_stream(buffer).await? == 0 {
+            return Err("Slave closed the connection".into());
+        }
+    }
+}
+
+// Challenge-response over a fresh nonce, returning the authenticated slave ID
+async fn authenticate_slave(
+    temp_slave: &Slave,
+    addr: std::net::IpAddr,
+    slave_auth: &SlaveAuth,
+    buffer: &mut BytesMut,
+    codec: &mut FrameCodec,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    // The address may have been blocked since the connection was accepted
+    if !slave_auth.begin_attempt(addr) {
+        return Err("too many failed attempts".into());
+    }
+    // Legacy slaves never got the auth command
+    if temp_slave.protocol_version == LEGACY_PROTOCOL_VERSION {
+        return Err("slave does not speak the hello protocol".into());
+    }
+
+    let nonce: [u8; NONCE_LEN] = SlaveAuth::challenge();
+    temp_slave.write_stream(&build_auth_challenge_command(&nonce)).await?;
+    let frame = match time::timeout(CLIENT_REQUEST_TIMEOUT, read_frame(temp_slave, buffer, codec)).await {
+        Ok(frame) => frame?,
+        Err(_) => return Err("auth response timed out".into()),
+    };
+    if frame.command_type != Some(CommandType::Auth) {
+        return Err("expected an auth response".into());
+    }
+
+    let (slave_id, mac) = parse_auth_response(&frame.payload).ok_or("malformed auth response")?;
+    if temp_slave
+        .slave_id
+        .as_ref()
+        .is_some_and(|hello_id| *hello_id != slave_id)
+    {
+        return Err(format!(
+            "authenticated as {} but connected as {:?}",
+            slave_id, temp_slave.slave_id
+        )
+        .into());
+    }
+    if !slave_auth.verify(&slave_id, &nonce, mac) {
+        return Err(format!("invalid credentials for {}", slave_id).into());
+    }
+    Ok(slave_id)
+}
+
 async fn verify_slave_session(
     temp_slave: &mut Slave,
+    proxy_manager: &ProxyManager,
     allowed_locations: &Arc<Vec<String>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Step 1: Perform Version Check
-    let version_command = build_version_check_command();
-    temp_slave.write_stream(&version_command).await?;
+    version_policy: &VersionPolicy,
+    slave_auth: &SlaveAuth,
+    codec: &mut FrameCodec,
+) -> Result<BytesMut, Box<dyn std::error::Error + Send + Sync>> {
     let mut buffer = BytesMut::with_capacity(MAX_BUF_SIZE);
 
-    if let Err(_) = time::timeout(CLIENT_REQUEST_TIMEOUT, temp_slave.read_stream(&mut buffer)).await {
-        return Err("Version check response timed out".into());
+    // Step 0: Negotiate the protocol version and capabilities. Slaves that
+    // don't answer the hello speak the legacy protocol without extras.
+    temp_slave.write_stream(&build_hello_command(&MASTER_HELLO)).await?;
+    match time::timeout(HELLO_TIMEOUT, read_frame(temp_slave, &mut buffer, codec)).await {
+        Ok(frame) => {
+            let frame = frame?;
+            if frame.command_type != Some(CommandType::Hello) {
+                return Err("Expected a hello reply".into());
+            }
+            let hello = parse_hello(&frame.payload).ok_or("Malformed hello reply")?;
+            let (version, capabilities) = MASTER_HELLO.negotiate(&hello).ok_or_else(|| {
+                format!(
+                    "Slave {} speaks protocol v{}-v{}, no overlap with v{}-v{}",
+                    temp_slave.ip_addr,
+                    hello.min_version,
+                    hello.max_version,
+                    MASTER_HELLO.min_version,
+                    MASTER_HELLO.max_version
+                )
+            })?;
+            temp_slave.set_protocol(version, capabilities);
+            if let Some(slave_id) = hello.slave_id {
+                if temp_slave.slave_id.as_ref().is_some_and(|cert_id| *cert_id != slave_id) {
+                    return Err(format!(
+                        "Slave {} said hello as {} but its certificate names {:?}",
+                        temp_slave.ip_addr, slave_id, temp_slave.slave_id
+                    )
+                    .into());
+                }
+                temp_slave.slave_id = Some(slave_id);
+            }
+        }
+        Err(_) => trace!(
+            "Slave {} did not answer hello, assuming legacy protocol",
+            temp_slave.ip_addr
+        ),
+    }
+    debug!(
+        "Slave {} negotiated protocol v{} with capabilities {:?}",
+        temp_slave.ip_addr,
+        temp_slave.protocol_version,
+        temp_slave.capabilities.names()
+    );
+
+    // Prove the slave holds a configured secret before trusting anything it says
+    if slave_auth.is_enabled() {
+        let addr: std::net::IpAddr = temp_slave.ip_addr.parse()?;
+        match authenticate_slave(temp_slave, addr, slave_auth, &mut buffer, codec).await {
+            Ok(slave_id) => {
+                slave_auth.record_success(addr);
+                info!("Slave {} authenticated as {}", temp_slave.ip_addr, slave_id);
+                // The fleet secret is shared, it doesn't tell slaves apart
+                temp_slave.id_verified |= slave_auth.has_own_secret(&slave_id);
+                temp_slave.slave_id = Some(slave_id);
+            }
+            Err(e) => return Err(format!("Slave {} failed authentication: {}", temp_slave.ip_addr, e).into()),
+        }
     }
-    let (_, _, payload_len, _) = parse_header(&buffer);
 
-    if payload_len == 0 || buffer.len() < 10 + payload_len {
+    // Step 1: Perform Version Check
+    let version_command = build_version_check_command();
+    temp_slave.write_stream(&version_command).await?;
+
+    let mut late_hello = temp_slave.protocol_version == LEGACY_PROTOCOL_VERSION;
+    let frame = loop {
+        let frame = match time::timeout(CLIENT_REQUEST_TIMEOUT, read_frame(temp_slave, &mut buffer, codec)).await {
+            Ok(frame) => frame?,
+            Err(_) => return Err("Version check response timed out".into()),
+        };
+        // A hello reply that missed HELLO_TIMEOUT; the slave stays on the legacy protocol
+        if late_hello && frame.command_type == Some(CommandType::Hello) {
+            trace!("Ignoring late hello reply from slave {}", temp_slave.ip_addr);
+            late_hello = false;
+            continue;
+        }
+        break frame;
+    };
+    if frame.payload.is_empty() {
         return Err("Invalid or empty version response".into());
     }
 
-    buffer.advance(10);
-    let version = String::from_utf8(buffer.split_to(payload_len).to_vec())?;
-    if !ALLOWED_SLAVE_VERSIONS.contains(&version.as_str()) {
-        return Err(format!("Slave {} has unsupported version: {}", temp_slave.ip_addr, version).into());
+    let version = String::from_utf8(frame.payload.to_vec())?;
+    if let Err(e) = version_policy.check(&version) {
+        return Err(format!("Slave {} has unsupported {}", temp_slave.ip_addr, e).into());
     }
     temp_slave.set_version(version.clone());
     trace!("Slave {} version check passed: {}", temp_slave.ip_addr, version);
 
+    // Further connections of a registered slave skip the location and speed
+    // checks, they must come from the same address though
+    if let Some(primary) = temp_slave
+        .slave_id
+        .as_deref()
+        .and_then(|id| proxy_manager.slave_by_id(id))
+    {
+        if primary.ip_addr != temp_slave.ip_addr {
+            return Err(format!(
+                "Slave ID {:?} is registered from {}, not {}",
+                temp_slave.slave_id, primary.ip_addr, temp_slave.ip_addr
+            )
+            .into());
+        }
+        temp_slave.inherit_profile(&primary);
+        return Ok(buffer);
+    }
+
     // Step 2: Perform Geolocation Check
     let location_command = build_location_check_command(&temp_slave.ip_addr);
     temp_slave.write_stream(&location_command).await?;
-    buffer.clear();
-
-    if let Err(_) = time::timeout(CLIENT_REQUEST_TIMEOUT, temp_slave.read_stream(&mut buffer)).await {
-        return Err("Location check response timed out".into());
-    }
-    
-    let (_, _, payload_len, _) = parse_header(&buffer);
 
-    if payload_len == 0 || buffer.len() < 10 + payload_len {
+    let frame = match time::timeout(CLIENT_REQUEST_TIMEOUT, read_frame(temp_slave, &mut buffer, codec)).await {
+        Ok(frame) => frame?,
+        Err(_) => return Err("Location check response timed out".into()),
+    };
+    if frame.payload.is_empty() {
         return Err("Location check response is empty".into());
     }
 
-    buffer.advance(10);
-    let location_data = String::from_utf8(buffer.split_to(payload_len).to_vec())?;
+    let location_data = String::from_utf8(frame.payload.to_vec())?;
     trace!("Received location data: {}", location_data);
 
     // Parse the location response
     let location: serde_json::Value = serde_json::from_str(&location_data)?;
     if let Some(country) = location["data"]["country"].as_str() {
         temp_slave.set_location(country.to_string());
+        temp_slave.set_geo(
+            location["data"]["region"].as_str().map(str::to_string),
+            location["data"]["city"].as_str().map(str::to_string),
+            location["data"]["asn"]["asn"]
+                .as_str()
+                .and_then(|asn| asn.trim_start_matches("AS").parse().ok()),
+        );
 
         // If allowed_locations is not empty, validate the country
         if !allowed_locations.is_empty() {
             if !allowed_locations.iter().any(|loc| loc.eq_ignore_ascii_case(country)) {
-                return Err(format!(
-                    "Slave {} is in a restricted location: {}",
-                    temp_slave.ip_addr, country
-                )
-                .into());
+                return Err(format!("Slave {} is in a restricted location: {}", temp_slave.ip_addr, country).into());
             }
             trace!("Slave {} location check passed: {}", temp_slave.ip_addr, country);
         }
@@ -247,24 +506,20 @@ async fn verify_slave_session(
     // Step 3: Perform Speed Test
     let speed_test_command = build_speed_test_command("https://speed.cloudflare.com/__down?bytes=5000000");
     temp_slave.write_stream(&speed_test_command).await?;
-    buffer.clear();
 
-    if let Err(_) = time::timeout(CLIENT_REQUEST_TIMEOUT, temp_slave.read_stream(&mut buffer)).await {
-        return Err("Speed test response timed out".into());
-    }
-
-    let (_, _, payload_len, _) = parse_header(&buffer);
-
-    if payload_len == 0 || buffer.len() < 10 + payload_len {
+    let frame = match time::timeout(CLIENT_REQUEST_TIMEOUT, read_frame(temp_slave, &mut buffer, codec)).await {
+        Ok(frame) => frame?,
+        Err(_) => return Err("Speed test response timed out".into()),
+    };
+    if frame.payload.is_empty() {
         return Err("Invalid or empty speed test response".into());
     }
 
-    buffer.advance(10);
-    let speed_str = String::from_utf8(buffer.split_to(payload_len).to_vec())?;
+    let speed_str = String::from_utf8(frame.payload.to_vec())?;
     let speed = speed_str.parse::<f64>()?;
     temp_slave.set_speed(speed);
     trace!("Slave {} speed test passed: {:.2} Mbps", temp_slave.ip_addr, speed);
 
-    Ok(())
+    // Whatever followed the last response belongs to the session traffic
+    Ok(buffer)
 }
-
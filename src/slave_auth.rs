@@ -0,0 +1,198 @@
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::time::{interval, Duration, Instant};
+
+pub const NONCE_LEN: usize = 32;
+// Addresses failing this often within the window are refused until it passes
+const MAX_FAILURES: u32 = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(300);
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Secrets slaves prove they know before they may register: one per slave ID,
+// plus an optional fleet-wide secret for IDs without their own. With neither
+// configured every slave is let in.
+pub struct SlaveAuth {
+    fleet_secret: Option<Vec<u8>>,
+    secrets: HashMap<String, Vec<u8>>,
+    // Source address -> failed attempts and when the first of them happened
+    failures: DashMap<IpAddr, (u32, Instant)>,
+}
+
+impl SlaveAuth {
+    pub fn new(fleet_secret: Option<String>, secrets: HashMap<String, String>) -> Self {
+        Self {
+            fleet_secret: fleet_secret.map(String::into_bytes),
+            secrets: secrets
+                .into_iter()
+                .map(|(slave_id, secret)| (slave_id, secret.into_bytes()))
+                .collect(),
+            failures: DashMap::new(),
+        }
+    }
+
+    pub fn from_file(path: &str, fleet_secret: Option<String>) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let secrets = parse_secrets(&content).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(Self::new(fleet_secret, secrets))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.fleet_secret.is_some() || !self.secrets.is_empty()
+    }
+
+    // Whether the ID has a secret of its own rather than the fleet one
+    pub fn has_own_secret(&self, slave_id: &str) -> bool {
+        self.secrets.contains_key(slave_id)
+    }
+
+    pub fn challenge() -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        nonce
+    }
+
+    // Check the slave's MAC over our nonce in constant time
+    pub fn verify(&self, slave_id: &str, nonce: &[u8], mac: &[u8]) -> bool {
+        let secret = match self.secrets.get(slave_id).or(self.fleet_secret.as_ref()) {
+            Some(secret) => secret,
+            None => return false,
+        };
+        let mut expected = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+        expected.update(nonce);
+        expected.update(slave_id.as_bytes());
+        expected.verify_slice(mac).is_ok()
+    }
+
+    pub fn is_blocked(&self, addr: IpAddr) -> bool {
+        self.failures
+            .get(&addr)
+            .is_some_and(|entry| entry.0 >= MAX_FAILURES && entry.1.elapsed() < FAILURE_WINDOW)
+    }
+
+    // Count an attempt before the nonce goes out, refusing it once the address
+    // is blocked. Attempts count as failures until record_success clears them,
+    // so concurrent handshakes from one address can't each get a guess in
+    // before the first failure lands.
+    pub fn begin_attempt(&self, addr: IpAddr) -> bool {
+        let mut entry = self.failures.entry(addr).or_insert((0, Instant::now()));
+        if entry.1.elapsed() >= FAILURE_WINDOW {
+            *entry = (0, Instant::now());
+        }
+        if entry.0 >= MAX_FAILURES {
+            return false;
+        }
+        entry.0 += 1;
+        true
+    }
+
+    pub fn record_success(&self, addr: IpAddr) {
+        self.failures.remove(&addr);
+    }
+
+    // Forget failures whose window has passed, they no longer block anyone
+    pub fn purge_expired(&self) {
+        self.failures.retain(|_, entry| entry.1.elapsed() < FAILURE_WINDOW);
+    }
+
+    // Purge expired failures every window so addresses that stopped trying
+    // don't stay in the map
+    pub fn spawn_sweeper(self: &Arc<Self>) {
+        let auth = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = interval(FAILURE_WINDOW);
+            loop {
+                ticker.tick().await;
+                auth.purge_expired();
+            }
+        });
+    }
+}
+
+// Secrets file, one "slave_id secret" per line, '#' starts a comment
+pub fn parse_secrets(content: &str) -> Result<HashMap<String, String>, String> {
+    let mut secrets = HashMap::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((slave_id, secret)) if !secret.trim().is_empty() => {
+                secrets.insert(slave_id.to_string(), secret.trim().to_string());
+            }
+            // Don't echo the line, it may hold a secret
+            _ => return Err("Invalid slave secrets line, expected \"slave_id secret\"".to_string()),
+        }
+    }
+    Ok(secrets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, nonce: &[u8], slave_id: &str) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(nonce);
+        mac.update(slave_id.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    #[test]
+    fn per_slave_and_fleet_secrets() {
+        let secrets = parse_secrets("# edge nodes\nedge-1 s3cret\n").unwrap();
+        let auth = SlaveAuth::new(Some("fleet".to_string()), secrets);
+        let nonce = SlaveAuth::challenge();
+
+        assert!(auth.verify("edge-1", &nonce, &sign("s3cret", &nonce, "edge-1")));
+        // Slaves with their own secret can't fall back to the fleet one
+        assert!(!auth.verify("edge-1", &nonce, &sign("fleet", &nonce, "edge-1")));
+        assert!(auth.verify("edge-2", &nonce, &sign("fleet", &nonce, "edge-2")));
+        // The MAC is bound to the ID and the nonce
+        assert!(!auth.verify("edge-3", &nonce, &sign("fleet", &nonce, "edge-2")));
+        assert!(!auth.verify("edge-2", &SlaveAuth::challenge(), &sign("fleet", &nonce, "edge-2")));
+
+        assert!(auth.has_own_secret("edge-1"));
+        assert!(!auth.has_own_secret("edge-2"));
+
+        assert!(!SlaveAuth::new(None, HashMap::new()).is_enabled());
+        assert!(parse_secrets("edge-1\n").is_err());
+    }
+
+    #[test]
+    fn repeated_failures_are_blocked() {
+        let auth = SlaveAuth::new(Some("fleet".to_string()), HashMap::new());
+        let addr: IpAddr = "192.0.2.7".parse().unwrap();
+        for _ in 0..MAX_FAILURES - 1 {
+            assert!(auth.begin_attempt(addr));
+        }
+        assert!(!auth.is_blocked(addr));
+        assert!(auth.begin_attempt(addr));
+        assert!(auth.is_blocked(addr));
+        assert!(!auth.begin_attempt(addr));
+        assert!(!auth.is_blocked("192.0.2.8".parse().unwrap()));
+
+        auth.record_success(addr);
+        assert!(!auth.is_blocked(addr));
+        assert!(auth.begin_attempt(addr));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_failures_are_purged() {
+        let auth = SlaveAuth::new(Some("fleet".to_string()), HashMap::new());
+        auth.begin_attempt("192.0.2.7".parse().unwrap());
+        tokio::time::advance(FAILURE_WINDOW / 2).await;
+        auth.begin_attempt("192.0.2.8".parse().unwrap());
+
+        tokio::time::advance(FAILURE_WINDOW / 2).await;
+        auth.purge_expired();
+        assert_eq!(auth.failures.len(), 1);
+        assert!(auth.failures.contains_key(&"192.0.2.8".parse().unwrap()));
+    }
+}
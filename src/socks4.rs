@@ -0,0 +1,198 @@
+use crate::auth::{AuthPolicy, ClientAuth, User};
+use crate::routing::{parse_username, RouteRequest};
+use crate::socks5::{ClientProtocol, ClientRequest, Socks5Command, REPLY_SUCCEEDED};
+use crate::tls::ClientStream;
+
+use bytes::BytesMut;
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+
+const REPLY_GRANTED: u8 = 0x5A;
+const REPLY_REJECTED: u8 = 0x5B;
+// USERID and the SOCKS4a domain are null-terminated, bound them
+const MAX_REQUEST_SIZE: usize = 512;
+
+#[derive(Debug, PartialEq)]
+pub struct Socks4Request {
+    pub address: String,
+    pub port: u16,
+    pub userid: String,
+}
+
+// Only the status matters to SOCKS4 clients on CONNECT, the address is zeroed
+pub async fn send_socks4_reply(client_stream: &mut ClientStream, reply: u8) -> Result<(), std::io::Error> {
+    let status = if reply == REPLY_SUCCEEDED {
+        REPLY_GRANTED
+    } else {
+        REPLY_REJECTED
+    };
+    client_stream.write_all(&[0x00, status, 0, 0, 0, 0, 0, 0]).await
+}
+
+// SOCKS4 has no password field, so clients that authenticate send
+// "username:password" as the USERID. Routing hints go in the username just
+// like with SOCKS5.
+pub async fn handle_socks4_handshake(
+    client_stream: &mut ClientStream,
+    auth: &ClientAuth,
+) -> Result<ClientRequest, std::io::Error> {
+    let handshake_timeout = Duration::from_secs(5);
+    let mut buffer = BytesMut::with_capacity(64);
+
+    let (request, len) = loop {
+        let read = timeout(handshake_timeout, client_stream.read_buf(&mut buffer)).await??;
+        if read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Incomplete SOCKS4 request",
+            ));
+        }
+        match parse_request(&buffer) {
+            Ok(Some(parsed)) => break parsed,
+            Ok(None) if buffer.len() < MAX_REQUEST_SIZE => continue,
+            Ok(None) => return Err(reject(client_stream, "SOCKS4 request too large".to_string()).await),
+            Err(e) => return Err(reject(client_stream, e).await),
+        }
+    };
+
+    let (name, password) = match request.userid.split_once(':') {
+        Some((name, password)) => (name, Some(password)),
+        None => (request.userid.as_str(), None),
+    };
+
+    let (user, route) = if name.is_empty() {
+        (None, RouteRequest::default())
+    } else {
+        let (username, route) = match parse_username(name) {
+            Ok(parsed) => parsed,
+            Err(e) => return Err(reject(client_stream, e).await),
+        };
+        (
+            Some(User {
+                username,
+                ..Default::default()
+            }),
+            route,
+        )
+    };
+
+    let user = match (auth.policy, user, password) {
+        (AuthPolicy::NoAuth, user, _) => user,
+        (_, Some(user), Some(password)) => match auth.authenticate(user.username, password).await {
+            Some(user) => Some(user),
+            None => return Err(reject(client_stream, format!("SOCKS4 authentication failed for {}", name)).await),
+        },
+        (AuthPolicy::Optional, user, _) => user,
+        // Without a credential backend a bare USERID is enough
+        (AuthPolicy::Required, Some(user), None) if auth.credentials.is_none() => Some(user),
+        (AuthPolicy::Required, _, _) => {
+            return Err(reject(
+                client_stream,
+                "SOCKS4 client did not send username:password".to_string(),
+            )
+            .await)
+        }
+    };
+
+    Ok(ClientRequest {
+        protocol: ClientProtocol::Socks4,
+        command: Socks5Command::Connect,
+        user,
+        route,
+        address: request.address,
+        port: request.port,
+        initial_data: buffer.split_off(len).freeze(),
+    })
+}
+
+async fn reject(client_stream: &mut ClientStream, msg: String) -> std::io::Error {
+    let _ = client_stream.write_all(&[0x00, REPLY_REJECTED, 0, 0, 0, 0, 0, 0]).await;
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+// VN CD DSTPORT DSTIP USERID\0, followed by DOMAIN\0 for SOCKS4a when DSTIP is
+// 0.0.0.x with x != 0. Returns None until the request is complete.
+pub fn parse_request(buf: &[u8]) -> Result<Option<(Socks4Request, usize)>, String> {
+    if buf.len() < 8 {
+        return Ok(None);
+    }
+    if buf[0] != 0x04 {
+        return Err("Invalid SOCKS4 version".to_string());
+    }
+    if buf[1] != 0x01 {
+        return Err("Only the SOCKS4 CONNECT command is supported".to_string());
+    }
+
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+    let (userid, mut len) = match read_cstr(buf, 8)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+
+    let octets = ip.octets();
+    let address = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let (domain, end) = match read_cstr(buf, len)? {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+        if domain.is_empty() {
+            return Err("Empty SOCKS4a domain".to_string());
+        }
+        len = end;
+        domain
+    } else {
+        ip.to_string()
+    };
+
+    Ok(Some((Socks4Request { address, port, userid }, len)))
+}
+
+// Null-terminated string starting at `start`, with the offset just past the terminator
+fn read_cstr(buf: &[u8], start: usize) -> Result<Option<(String, usize)>, String> {
+    match buf[start..].iter().position(|&b| b == 0) {
+        Some(end) => {
+            let value = String::from_utf8(buf[start..start + end].to_vec())
+                .map_err(|_| "Invalid UTF-8 in SOCKS4 request".to_string())?;
+            Ok(Some((value, start + end + 1)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socks4_and_socks4a_requests() {
+        let request = b"\x04\x01\x00\x50\xc0\x00\x02\x01user-country-us\x00";
+        assert_eq!(
+            parse_request(request).unwrap(),
+            Some((
+                Socks4Request {
+                    address: "192.0.2.1".to_string(),
+                    port: 80,
+                    userid: "user-country-us".to_string(),
+                },
+                request.len()
+            ))
+        );
+
+        let request = b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00rest";
+        let (parsed, len) = parse_request(request).unwrap().unwrap();
+        assert_eq!(parsed.address, "example.com");
+        assert_eq!(parsed.port, 443);
+        assert_eq!(parsed.userid, "");
+        assert_eq!(&request[len..], b"rest");
+
+        // Incomplete until the domain is terminated
+        assert_eq!(
+            parse_request(b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example").unwrap(),
+            None
+        );
+        assert!(parse_request(b"\x04\x02\x00\x50\xc0\x00\x02\x01\x00").is_err());
+    }
+}
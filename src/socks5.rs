@@ -1,143 +1,620 @@
-use tokio::{io::{AsyncWriteExt, AsyncReadExt}, net::TcpStream};
+use crate::auth::{AuthPolicy, ClientAuth, User};
+use crate::packet::SessionStatus;
+use crate::routing::{parse_username, RouteRequest};
+use crate::socks4::handle_socks4_handshake;
+use crate::tls::ClientStream;
+use crate::utils::put_socket_addr;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::{timeout, Duration};
 
-pub async fn handle_client_handshake(
-    client_stream: &mut TcpStream,
-) -> Result<(Option<String>, String, u16), std::io::Error> {
-    let mut buffer = [0u8; 512];
-    let handshake_timeout = Duration::from_secs(5);
+// RFC 1928 reply codes
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
 
-    // Step 1: SOCKS5 Greeting
-    let len = timeout(handshake_timeout, client_stream.read(&mut buffer)).await??;
-    if len < 2 || buffer[0] != 0x05 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid SOCKS5 greeting packet",
-        ));
-    }
-
-    let auth_methods = &buffer[2..len];
-    if !auth_methods.contains(&0x00) && !auth_methods.contains(&0x02) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Unsupported authentication methods",
-        ));
-    }
-
-    let username = if auth_methods.contains(&0x02) {
-        // Username/password authentication
-        client_stream.write_all(&[0x05, 0x02]).await?;
-        let len = timeout(handshake_timeout, client_stream.read(&mut buffer)).await??;
-        if len < 3 || buffer[0] != 0x01 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid username/password authentication packet",
-            ));
+// RFC 1928 authentication methods
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+pub fn reply_code(status: SessionStatus) -> u8 {
+    match status {
+        SessionStatus::Success => REPLY_SUCCEEDED,
+        SessionStatus::GeneralFailure => REPLY_GENERAL_FAILURE,
+        SessionStatus::Refused => REPLY_CONNECTION_REFUSED,
+        SessionStatus::HostUnreachable => REPLY_HOST_UNREACHABLE,
+        SessionStatus::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        SessionStatus::TtlExpired => REPLY_TTL_EXPIRED,
+        SessionStatus::NotAllowed => REPLY_NOT_ALLOWED,
+    }
+}
+
+// Send the final reply of the request phase, with 0.0.0.0:0 when no bound address is known
+pub async fn send_reply(
+    client_stream: &mut ClientStream,
+    reply: u8,
+    bound_addr: Option<SocketAddr>,
+) -> Result<(), std::io::Error> {
+    let bound_addr = bound_addr.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    let mut frame = BytesMut::with_capacity(22);
+    frame.put_slice(&[0x05, reply, 0x00]);
+    put_socket_addr(&mut frame, &bound_addr);
+    client_stream.write_all(&frame).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Socks5Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+// Client protocol a request arrived over, which decides how it is answered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientProtocol {
+    Socks4,
+    Socks5,
+    HttpConnect,
+    // Absolute-URI requests, answered by the origin server itself
+    HttpForward,
+}
+
+// Outcome of a successful handshake, before the slave is asked to connect
+#[derive(Debug)]
+pub struct ClientRequest {
+    pub protocol: ClientProtocol,
+    pub command: Socks5Command,
+    pub user: Option<User>,
+    pub route: RouteRequest,
+    pub address: String,
+    pub port: u16,
+    // Bytes to forward to the destination as soon as the session is up
+    pub initial_data: Bytes,
+}
+
+// Length of the ATYP + address + port block at the start of buf
+pub fn socks_addr_len(buf: &[u8]) -> Option<usize> {
+    let len = match *buf.first()? {
+        0x01 => 1 + 4 + 2,
+        0x03 => 1 + 1 + *buf.get(1)? as usize + 2,
+        0x04 => 1 + 16 + 2,
+        _ => return None,
+    };
+    (buf.len() >= len).then_some(len)
+}
+
+// Strip the RSV/FRAG prefix of a SOCKS5 UDP request, leaving ATYP + address +
+// port + data. Fragmented datagrams are not supported and yield None.
+pub fn strip_udp_header(datagram: &[u8]) -> Option<&[u8]> {
+    if datagram.len() < 3 || datagram[0] != 0 || datagram[1] != 0 || datagram[2] != 0 {
+        return None;
+    }
+    let payload = &datagram[3..];
+    socks_addr_len(payload)?;
+    Some(payload)
+}
+
+// SOCKS4 and SOCKS5 clients share a port and are told apart by the version byte
+pub async fn handle_socks_handshake(
+    client_stream: &mut ClientStream,
+    auth: &ClientAuth,
+) -> Result<ClientRequest, std::io::Error> {
+    let mut version = [0u8; 1];
+    timeout(Duration::from_secs(5), client_stream.peek(&mut version)).await??;
+    match version[0] {
+        0x04 => handle_socks4_handshake(client_stream, auth).await,
+        _ => handle_client_handshake(client_stream, auth).await,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HandshakeState {
+    Greeting,
+    // Waiting for the server to pick an authentication method
+    MethodSelection,
+    Auth,
+    Request,
+    Done,
+}
+
+// A complete handshake message from the client
+#[derive(Debug, PartialEq)]
+pub enum HandshakeMessage {
+    Greeting(Vec<u8>),
+    Auth { username: String, password: String },
+    Request { command: u8, address: String, port: u16 },
+}
+
+// Incremental SOCKS5 handshake parser. Bytes are fed as they arrive and
+// messages come out once complete, so greeting, auth and request may be split
+// across reads or coalesced into one. Anything after the request is left
+// buffered for the destination.
+pub struct HandshakeParser {
+    state: HandshakeState,
+    buffer: BytesMut,
+}
+
+impl Default for HandshakeParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HandshakeParser {
+    pub fn new() -> Self {
+        Self {
+            state: HandshakeState::Greeting,
+            buffer: BytesMut::with_capacity(512),
         }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    // Username/password is the only method with a sub-negotiation
+    pub fn select_method(&mut self, method: u8) {
+        if self.state == HandshakeState::MethodSelection {
+            self.state = if method == METHOD_USERNAME_PASSWORD {
+                HandshakeState::Auth
+            } else {
+                HandshakeState::Request
+            };
+        }
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<HandshakeMessage>, std::io::Error> {
+        let parsed = match self.state {
+            HandshakeState::Greeting => parse_greeting(&self.buffer)?,
+            HandshakeState::Auth => parse_auth(&self.buffer)?,
+            HandshakeState::Request => parse_request(&self.buffer)?,
+            HandshakeState::MethodSelection | HandshakeState::Done => None,
+        };
+        let Some((message, len)) = parsed else {
+            return Ok(None);
+        };
+
+        self.buffer.advance(len);
+        self.state = match self.state {
+            HandshakeState::Greeting => HandshakeState::MethodSelection,
+            HandshakeState::Auth => HandshakeState::Request,
+            _ => HandshakeState::Done,
+        };
+        Ok(Some(message))
+    }
+
+    // Pipelined bytes the client sent past the request
+    pub fn into_remaining(self) -> Bytes {
+        self.buffer.freeze()
+    }
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// VER NMETHODS METHODS...
+fn parse_greeting(buf: &[u8]) -> Result<Option<(HandshakeMessage, usize)>, std::io::Error> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != 0x05 {
+        return Err(invalid_data("Invalid SOCKS5 greeting packet"));
+    }
+    let Some(&nmethods) = buf.get(1) else {
+        return Ok(None);
+    };
+    let len = 2 + nmethods as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    Ok(Some((HandshakeMessage::Greeting(buf[2..len].to_vec()), len)))
+}
+
+// VER ULEN UNAME PLEN PASSWD (RFC 1929)
+fn parse_auth(buf: &[u8]) -> Result<Option<(HandshakeMessage, usize)>, std::io::Error> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != 0x01 {
+        return Err(invalid_data("Invalid username/password authentication packet"));
+    }
+    let Some(&username_len) = buf.get(1) else {
+        return Ok(None);
+    };
+    let password_start = 3 + username_len as usize;
+    let Some(&password_len) = buf.get(password_start - 1) else {
+        return Ok(None);
+    };
+    let len = password_start + password_len as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let username = String::from_utf8(buf[2..password_start - 1].to_vec())
+        .map_err(|_| invalid_data("Invalid UTF-8 in username"))?;
+    let password =
+        String::from_utf8(buf[password_start..len].to_vec()).map_err(|_| invalid_data("Invalid UTF-8 in password"))?;
+    Ok(Some((HandshakeMessage::Auth { username, password }, len)))
+}
 
-        let username_len = buffer[1] as usize;
-        if len < 2 + username_len {
+// VER CMD RSV ATYP DST.ADDR DST.PORT
+fn parse_request(buf: &[u8]) -> Result<Option<(HandshakeMessage, usize)>, std::io::Error> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != 0x05 {
+        return Err(invalid_data("Invalid SOCKS5 request packet"));
+    }
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+
+    let addr = &buf[3..];
+    let addr_len = match addr[0] {
+        0x01 | 0x03 | 0x04 => match socks_addr_len(addr) {
+            Some(len) => len,
+            None => return Ok(None),
+        },
+        _ => {
             return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Incomplete username data",
-            ));
+                std::io::ErrorKind::Unsupported,
+                "Unsupported address type",
+            ))
         }
+    };
 
-        let username = String::from_utf8(buffer[2..2 + username_len].to_vec())
-            .map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid UTF-8 in username")
-            })?;
+    let port_at = addr_len - 2;
+    let port = u16::from_be_bytes([addr[port_at], addr[port_at + 1]]);
+    let address = match addr[0] {
+        0x01 => Ipv4Addr::new(addr[1], addr[2], addr[3], addr[4]).to_string(),
+        0x03 => String::from_utf8(addr[2..port_at].to_vec()).map_err(|_| invalid_data("Invalid UTF-8 in domain"))?,
+        _ => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[1..17]);
+            Ipv6Addr::from(octets)
+                .segments()
+                .iter()
+                .map(|segment| format!("{:x}", segment))
+                .collect::<Vec<_>>()
+                .join(":")
+        }
+    };
 
-        // Send authentication success response
-        client_stream.write_all(&[0x01, 0x00]).await?;
-        Some(username)
-    } else {
-        // No authentication required
-        client_stream.write_all(&[0x05, 0x00]).await?;
-        None
+    let message = HandshakeMessage::Request {
+        command: buf[1],
+        address,
+        port,
     };
+    Ok(Some((message, 3 + addr_len)))
+}
+
+// Read from the client until the parser yields its next message
+async fn read_message(
+    client_stream: &mut ClientStream,
+    parser: &mut HandshakeParser,
+) -> Result<HandshakeMessage, std::io::Error> {
+    let handshake_timeout = Duration::from_secs(5);
+    let mut chunk = [0u8; 512];
 
-    // Step 2: SOCKS5 Request Phase
-    let len = timeout(handshake_timeout, client_stream.read(&mut buffer)).await??;
-    if len < 10 || buffer[0] != 0x05 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid SOCKS5 request packet",
-        ));
+    loop {
+        if let Some(message) = parser.next_message()? {
+            return Ok(message);
+        }
+        let len = timeout(handshake_timeout, client_stream.read(&mut chunk)).await??;
+        if len == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Client closed during SOCKS5 handshake",
+            ));
+        }
+        parser.feed(&chunk[..len]);
     }
+}
 
-    if buffer[1] != 0x01 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Unsupported,
-            "Only CONNECT command is supported",
-        ));
+fn unexpected_message() -> std::io::Error {
+    invalid_data("Unexpected SOCKS5 handshake message")
+}
+
+// Pick the authentication method for the offered ones, None if nothing is acceptable
+pub fn select_method(policy: AuthPolicy, offered: &[u8]) -> Option<u8> {
+    let accepts = |method: u8| offered.contains(&method).then_some(method);
+    match policy {
+        AuthPolicy::Required => accepts(METHOD_USERNAME_PASSWORD),
+        AuthPolicy::NoAuth => accepts(METHOD_NO_AUTH),
+        AuthPolicy::Optional => accepts(METHOD_USERNAME_PASSWORD).or_else(|| accepts(METHOD_NO_AUTH)),
     }
+}
 
-    let addr_type = buffer[3];
-    let (address, port) = match addr_type {
-        0x01 => {
-            // IPv4
-            if len < 10 {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Incomplete IPv4 address",
-                ));
-            }
-            let ip = format!(
-                "{}.{}.{}.{}",
-                buffer[4], buffer[5], buffer[6], buffer[7]
-            );
-            let port = u16::from_be_bytes([buffer[8], buffer[9]]);
-            (ip, port)
+pub async fn handle_client_handshake(
+    client_stream: &mut ClientStream,
+    auth: &ClientAuth,
+) -> Result<ClientRequest, std::io::Error> {
+    let mut parser = HandshakeParser::new();
+
+    // Step 1: SOCKS5 Greeting
+    let HandshakeMessage::Greeting(auth_methods) = read_message(client_stream, &mut parser).await? else {
+        return Err(unexpected_message());
+    };
+
+    let method = match select_method(auth.policy, &auth_methods) {
+        Some(method) => method,
+        None => {
+            client_stream.write_all(&[0x05, METHOD_NO_ACCEPTABLE]).await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("No acceptable authentication method in {:?}", auth_methods),
+            ));
         }
-        0x03 => {
-            // Domain name
-            let domain_len = buffer[4] as usize;
-            if len < 5 + domain_len + 2 {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Incomplete domain name address",
-                ));
+    };
+    client_stream.write_all(&[0x05, method]).await?;
+    parser.select_method(method);
+
+    let (user, route) = if method == METHOD_USERNAME_PASSWORD {
+        // Username/password authentication (RFC 1929)
+        let HandshakeMessage::Auth { username, password } = read_message(client_stream, &mut parser).await? else {
+            return Err(unexpected_message());
+        };
+
+        // Routing hints ride along in the username and are stripped before authenticating
+        let (username, route) = match parse_username(&username) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                client_stream.write_all(&[0x01, 0x01]).await?;
+                return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, e));
             }
-            let domain = String::from_utf8(buffer[5..5 + domain_len].to_vec())
-                .map_err(|_| {
-                    std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid UTF-8 in domain")
-                })?;
-            let port = u16::from_be_bytes([buffer[5 + domain_len], buffer[6 + domain_len]]);
-            (domain, port)
-        }
-        0x04 => {
-            // IPv6
-            if len < 22 {
+        };
+
+        let user = match auth.authenticate(username.clone(), &password).await {
+            Some(user) => user,
+            None => {
+                client_stream.write_all(&[0x01, 0x01]).await?;
                 return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Incomplete IPv6 address",
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("Authentication failed for user {}", username),
                 ));
             }
-            let ip = format!(
-                "{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
-                u16::from_be_bytes([buffer[4], buffer[5]]),
-                u16::from_be_bytes([buffer[6], buffer[7]]),
-                u16::from_be_bytes([buffer[8], buffer[9]]),
-                u16::from_be_bytes([buffer[10], buffer[11]]),
-                u16::from_be_bytes([buffer[12], buffer[13]]),
-                u16::from_be_bytes([buffer[14], buffer[15]]),
-                u16::from_be_bytes([buffer[16], buffer[17]]),
-                u16::from_be_bytes([buffer[18], buffer[19]])
-            );
-            let port = u16::from_be_bytes([buffer[20], buffer[21]]);
-            (ip, port)
+        };
+
+        // Send authentication success response
+        client_stream.write_all(&[0x01, 0x00]).await?;
+        (Some(user), route)
+    } else {
+        (None, RouteRequest::default())
+    };
+
+    // Step 2: SOCKS5 Request Phase. Every failure from here on gets a reply
+    // frame before the connection is closed.
+    let message = match read_message(client_stream, &mut parser).await {
+        Ok(message) => message,
+        Err(e) => {
+            let reply = match e.kind() {
+                std::io::ErrorKind::Unsupported => Some(REPLY_ADDRESS_NOT_SUPPORTED),
+                std::io::ErrorKind::InvalidData | std::io::ErrorKind::TimedOut => Some(REPLY_GENERAL_FAILURE),
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                let _ = send_reply(client_stream, reply, None).await;
+            }
+            return Err(e);
         }
+    };
+    let HandshakeMessage::Request { command, address, port } = message else {
+        return Err(unexpected_message());
+    };
+
+    let command = match command {
+        0x01 => Socks5Command::Connect,
+        0x02 => Socks5Command::Bind,
+        0x03 => Socks5Command::UdpAssociate,
         _ => {
+            send_reply(client_stream, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
             return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Unsupported address type",
+                std::io::ErrorKind::Unsupported,
+                format!("Unsupported SOCKS5 command {:#04x}", command),
             ));
         }
     };
 
-    // Send success response
-    client_stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+    // The reply is sent by the caller once the slave has tried to connect
+    Ok(ClientRequest {
+        protocol: ClientProtocol::Socks5,
+        command,
+        user,
+        route,
+        address,
+        port,
+        initial_data: parser.into_remaining(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    const PIPELINED: &[u8] = b"\x05\x01\x02\x01\x05alice\x02pw\x05\x01\x00\x03\x0bexample.com\x01\xbbGET /";
+
+    // Drive the parser the way the handshake does, answering with username/password
+    fn run(parser: &mut HandshakeParser, input: &[u8], chunk: usize) -> Vec<HandshakeMessage> {
+        let mut messages = Vec::new();
+        for piece in input.chunks(chunk) {
+            parser.feed(piece);
+            while let Some(message) = parser.next_message().unwrap() {
+                if matches!(message, HandshakeMessage::Greeting(_)) {
+                    parser.select_method(0x02);
+                }
+                messages.push(message);
+            }
+        }
+        messages
+    }
+
+    fn expected() -> Vec<HandshakeMessage> {
+        vec![
+            HandshakeMessage::Greeting(vec![0x02]),
+            HandshakeMessage::Auth {
+                username: "alice".to_string(),
+                password: "pw".to_string(),
+            },
+            HandshakeMessage::Request {
+                command: 0x01,
+                address: "example.com".to_string(),
+                port: 443,
+            },
+        ]
+    }
+
+    #[test]
+    fn parses_byte_at_a_time() {
+        let mut parser = HandshakeParser::new();
+        assert_eq!(run(&mut parser, PIPELINED, 1), expected());
+        assert_eq!(parser.into_remaining(), Bytes::from_static(b"GET /"));
+    }
+
+    #[test]
+    fn parses_all_at_once() {
+        let mut parser = HandshakeParser::new();
+        assert_eq!(run(&mut parser, PIPELINED, PIPELINED.len()), expected());
+        assert_eq!(parser.into_remaining(), Bytes::from_static(b"GET /"));
+    }
+
+    #[test]
+    fn address_types() {
+        let mut parser = HandshakeParser::new();
+        parser.feed(
+            b"\x05\x01\x00\x05\x03\x00\x04\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x50",
+        );
+        assert!(parser.next_message().unwrap().is_some());
+        parser.select_method(0x00);
+        assert_eq!(
+            parser.next_message().unwrap(),
+            Some(HandshakeMessage::Request {
+                command: 0x03,
+                address: "2001:db8:0:0:0:0:0:1".to_string(),
+                port: 80,
+            })
+        );
+
+        let mut parser = HandshakeParser::new();
+        parser.feed(b"\x05\x01\x00\x05\x01\x00\x05");
+        parser.next_message().unwrap();
+        parser.select_method(0x00);
+        assert!(parser.next_message().is_err());
+    }
+
+    #[tokio::test]
+    async fn pipelined_handshake_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let mut server = ClientStream::from(listener.accept().await.unwrap().0);
+
+        client.write_all(PIPELINED).await.unwrap();
+        let auth = ClientAuth::new(AuthPolicy::Optional, None);
+        let request = handle_client_handshake(&mut server, &auth).await.unwrap();
+        assert_eq!(request.command, Socks5Command::Connect);
+        assert_eq!(request.user.unwrap().username, "alice");
+        assert_eq!(request.address, "example.com");
+        assert_eq!(request.port, 443);
+        assert_eq!(request.initial_data, Bytes::from_static(b"GET /"));
+
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [0x05, 0x02, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn udp_header_is_stripped() {
+        let datagrams: [(&[u8], Option<&[u8]>); 10] = [
+            // IPv4, domain and IPv6 destinations keep ATYP + address + port + data
+            (
+                b"\x00\x00\x00\x01\x08\x08\x08\x08\x00\x35dns",
+                Some(b"\x01\x08\x08\x08\x08\x00\x35dns"),
+            ),
+            (b"\x00\x00\x00\x03\x04host\x00\x35", Some(b"\x03\x04host\x00\x35")),
+            (
+                b"\x00\x00\x00\x04\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x35q",
+                Some(b"\x04\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x35q"),
+            ),
+            // Fragments and a non-zero RSV are dropped
+            (b"\x00\x00\x01\x01\x08\x08\x08\x08\x00\x35dns", None),
+            (b"\x00\x01\x00\x01\x08\x08\x08\x08\x00\x35dns", None),
+            // Truncated headers and unknown address types
+            (b"\x00\x00", None),
+            (b"\x00\x00\x00", None),
+            (b"\x00\x00\x00\x01\x08\x08\x08\x08\x00", None),
+            (b"\x00\x00\x00\x03\x09host\x00\x35", None),
+            (b"\x00\x00\x00\x05\x08\x08\x08\x08\x00\x35", None),
+        ];
+        for (datagram, payload) in datagrams {
+            assert_eq!(strip_udp_header(datagram), payload, "datagram {:?}", datagram);
+        }
+    }
+
+    #[test]
+    fn session_status_maps_to_reply_code() {
+        let codes = [
+            (SessionStatus::Success, REPLY_SUCCEEDED),
+            (SessionStatus::GeneralFailure, REPLY_GENERAL_FAILURE),
+            (SessionStatus::Refused, REPLY_CONNECTION_REFUSED),
+            (SessionStatus::HostUnreachable, REPLY_HOST_UNREACHABLE),
+            (SessionStatus::NetworkUnreachable, REPLY_NETWORK_UNREACHABLE),
+            (SessionStatus::TtlExpired, REPLY_TTL_EXPIRED),
+            (SessionStatus::NotAllowed, REPLY_NOT_ALLOWED),
+        ];
+        for (status, code) in codes {
+            assert_eq!(reply_code(status), code, "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn method_selection_follows_policy() {
+        assert_eq!(select_method(AuthPolicy::Required, &[0x00, 0x02]), Some(0x02));
+        assert_eq!(select_method(AuthPolicy::Required, &[0x00]), None);
+        assert_eq!(select_method(AuthPolicy::NoAuth, &[0x00, 0x02]), Some(0x00));
+        assert_eq!(select_method(AuthPolicy::NoAuth, &[0x02]), None);
+        assert_eq!(select_method(AuthPolicy::Optional, &[0x00]), Some(0x00));
+        assert_eq!(select_method(AuthPolicy::Optional, &[0x01]), None);
+    }
 
-    Ok((username, address, port))
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn failures_are_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let auth = ClientAuth::new(AuthPolicy::NoAuth, None);
+
+        // No acceptable method
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let mut server = ClientStream::from(listener.accept().await.unwrap().0);
+        client.write_all(b"\x05\x01\x02").await.unwrap();
+        assert!(handle_client_handshake(&mut server, &auth).await.is_err());
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0xFF]);
+
+        // Unknown command and unknown address type
+        for (request, code) in [
+            (
+                &b"\x05\x01\x00\x05\x09\x00\x01\x7f\x00\x00\x01\x00\x50"[..],
+                REPLY_COMMAND_NOT_SUPPORTED,
+            ),
+            (&b"\x05\x01\x00\x05\x01\x00\x07"[..], REPLY_ADDRESS_NOT_SUPPORTED),
+        ] {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let mut server = ClientStream::from(listener.accept().await.unwrap().0);
+            client.write_all(request).await.unwrap();
+            assert!(handle_client_handshake(&mut server, &auth).await.is_err());
+            let mut reply = [0u8; 12];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..4], [0x05, 0x00, 0x05, code]);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use bytes::BytesMut;
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+pub type SlaveReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type SlaveWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+// Transport under a slave link. Past the handshake nothing cares which it is.
+pub enum SlaveStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl SlaveStream {
+    pub fn into_split(self) -> (SlaveReader, SlaveWriter) {
+        match self {
+            SlaveStream::Plain(stream) => {
+                let (reader, writer) = stream.into_split();
+                (Box::new(reader), Box::new(writer))
+            }
+            SlaveStream::Tls(stream) => {
+                let (reader, writer) = tokio::io::split(*stream);
+                (Box::new(reader), Box::new(writer))
+            }
+        }
+    }
+
+    // Slave ID named by the verified client certificate, if one was presented
+    pub fn peer_identity(&self) -> Option<String> {
+        match self {
+            SlaveStream::Plain(_) => None,
+            SlaveStream::Tls(stream) => stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(certificate_identity),
+        }
+    }
+}
+
+impl From<TcpStream> for SlaveStream {
+    fn from(stream: TcpStream) -> Self {
+        SlaveStream::Plain(stream)
+    }
+}
+
+// Transport under a client connection. A TLS socket can't be peeked at, so
+// bytes read ahead of the handshake are kept for the next read instead.
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls {
+        stream: Box<TlsStream<TcpStream>>,
+        peeked: BytesMut,
+    },
+}
+
+impl ClientStream {
+    pub fn tls(stream: TlsStream<TcpStream>) -> Self {
+        ClientStream::Tls {
+            stream: Box::new(stream),
+            peeked: BytesMut::new(),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, ClientStream::Tls { .. })
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            ClientStream::Tls { stream, .. } => stream.get_ref().0,
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+
+    pub async fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.peek(buf).await,
+            ClientStream::Tls { stream, peeked } => {
+                if peeked.is_empty() {
+                    stream.read_buf(peeked).await?;
+                }
+                let len = peeked.len().min(buf.len());
+                buf[..len].copy_from_slice(&peeked[..len]);
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl From<TcpStream> for ClientStream {
+    fn from(stream: TcpStream) -> Self {
+        ClientStream::Plain(stream)
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls { stream, peeked } => {
+                if !peeked.is_empty() {
+                    let len = peeked.len().min(buf.remaining());
+                    buf.put_slice(&peeked.split_to(len));
+                    return Poll::Ready(Ok(()));
+                }
+                Pin::new(stream).poll_read(cx, buf)
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls { stream, .. } => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            ClientStream::Tls { stream, .. } => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            ClientStream::Plain(stream) => stream.is_write_vectored(),
+            ClientStream::Tls { stream, .. } => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls { stream, .. } => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls { stream, .. } => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+pub fn load_certs(path: &str) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("No certificates in {}", path),
+        ));
+    }
+    Ok(certs)
+}
+
+pub fn load_key(path: &str) -> std::io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("No private key in {}", path)))
+}
+
+// Acceptor for the slave listener. With a client CA every slave has to
+// present a certificate it signed, naming the slave ID it may register as.
+pub fn slave_acceptor(cert: &str, key: &str, client_ca: Option<&str>) -> std::io::Result<TlsAcceptor> {
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Acceptor for the TLS client listeners. Each pair is a certificate chain
+// and its key; clients get the one matching their SNI name, or the first.
+pub fn client_acceptor(pairs: &[(String, String)]) -> std::io::Result<TlsAcceptor> {
+    let mut by_name = HashMap::new();
+    let mut default = None;
+    for (cert, key) in pairs {
+        let certs = load_certs(cert)?;
+        let signing_key = any_supported_type(&load_key(key)?).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let names = certificate_names(&certs[0]);
+        let certified = Arc::new(CertifiedKey::new(certs, signing_key));
+        for name in names {
+            by_name.insert(name.to_ascii_lowercase(), Arc::clone(&certified));
+        }
+        default.get_or_insert(certified);
+    }
+    let default =
+        default.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No client TLS certificates configured"))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver { by_name, default }));
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[derive(Debug)]
+struct SniResolver {
+    // Certificate names, wildcards included as "*.example.com"
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl SniResolver {
+    fn lookup(&self, name: &str) -> Arc<CertifiedKey> {
+        let name = name.to_ascii_lowercase();
+        self.by_name
+            .get(&name)
+            .or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.by_name.get(&format!("*.{}", parent))
+            })
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(match hello.server_name() {
+            Some(name) => self.lookup(name),
+            None => self.default.clone(),
+        })
+    }
+}
+
+// DNS names of the certificate, or its common name when it has none
+fn certificate_names(cert: &CertificateDer) -> Vec<String> {
+    let Ok((_, cert)) = X509Certificate::from_der(cert) else {
+        return Vec::new();
+    };
+    let dns_names: Vec<String> = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if !dns_names.is_empty() {
+        return dns_names;
+    }
+    cert.subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .take(1)
+        .collect()
+}
+
+// Slave ID a client certificate names, its first DNS name or common name
+pub fn certificate_identity(cert: &CertificateDer) -> Option<String> {
+    certificate_names(cert).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsConnector;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[tokio::test]
+    async fn client_certificate_names_the_slave() {
+        let acceptor =
+            slave_acceptor(&fixture("master.crt"), &fixture("master.key"), Some(&fixture("ca.crt"))).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(load_certs(&fixture("ca.crt")).unwrap().remove(0)).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(
+                load_certs(&fixture("edge-1.crt")).unwrap(),
+                load_key(&fixture("edge-1.key")).unwrap(),
+            )
+            .unwrap();
+        let slave = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut stream = TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("master").unwrap(), stream)
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await.unwrap();
+            reply
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = SlaveStream::Tls(Box::new(acceptor.accept(stream).await.unwrap()));
+        assert_eq!(stream.peer_identity().as_deref(), Some("edge-1"));
+
+        let (mut reader, mut writer) = stream.into_split();
+        let mut hello = [0u8; 5];
+        reader.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        writer.write_all(b"ok").await.unwrap();
+        assert_eq!(&slave.await.unwrap(), b"ok");
+    }
+
+    #[tokio::test]
+    async fn client_listener_picks_certificate_by_sni() {
+        let pairs = [
+            (fixture("master.crt"), fixture("master.key")),
+            (fixture("edge-1.crt"), fixture("edge-1.key")),
+        ];
+        let acceptor = client_acceptor(&pairs).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(load_certs(&fixture("ca.crt")).unwrap().remove(0)).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        // The client verifies the name, so the handshake only completes with the right certificate
+        for name in ["master", "edge-1"] {
+            let connector = connector.clone();
+            let client = tokio::spawn(async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let mut stream = connector
+                    .connect(ServerName::try_from(name).unwrap(), stream)
+                    .await
+                    .unwrap();
+                stream.write_all(b"\x05\x01\x00").await.unwrap();
+                stream.flush().await.unwrap();
+                stream
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = ClientStream::tls(acceptor.accept(stream).await.unwrap());
+            assert!(stream.is_tls());
+            let mut version = [0u8; 1];
+            stream.peek(&mut version).await.unwrap();
+            assert_eq!(version, [0x05]);
+            // Peeked bytes are still there for the handshake to read
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(&greeting, b"\x05\x01\x00");
+            drop(client.await.unwrap());
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use bytes::{BufMut, BytesMut};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub const CLIENT_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
 
@@ -14,3 +16,36 @@ pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
     array.copy_from_slice(bytes);
     u32::from_be_bytes(array)
 }
+
+// Encode a socket address as SOCKS5 ATYP + address + port
+pub fn put_socket_addr(buf: &mut BytesMut, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.put_u8(0x01);
+            buf.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.put_u8(0x04);
+            buf.put_slice(&ip.octets());
+        }
+    }
+    buf.put_u16(addr.port());
+}
+
+// Decode a SOCKS5 ATYP + address + port, returning the address and bytes consumed
+pub fn get_socket_addr(buf: &[u8]) -> Option<(SocketAddr, usize)> {
+    match *buf.first()? {
+        0x01 if buf.len() >= 7 => {
+            let ip = Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]);
+            let port = u16::from_be_bytes([buf[5], buf[6]]);
+            Some((SocketAddr::new(IpAddr::V4(ip), port), 7))
+        }
+        0x04 if buf.len() >= 19 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[1..17]);
+            let port = u16::from_be_bytes([buf[17], buf[18]]);
+            Some((SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port), 19))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,168 @@
+use log::{error, info};
+use semver::{Version, VersionReq};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::time::{interval, Duration};
+
+pub const DEFAULT_SLAVE_VERSION_REQ: &str = ">=1.0.9";
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// Which slave versions may register: a semver requirement plus known-bad
+// versions that are refused even when they match it
+#[derive(Debug)]
+pub struct VersionPolicy {
+    requirement: VersionReq,
+    denied: Vec<Version>,
+}
+
+impl VersionPolicy {
+    // `deny` is a comma-separated list of exact versions
+    pub fn parse(requirement: &str, deny: &str) -> Result<Self, String> {
+        let requirement = VersionReq::parse(requirement)
+            .map_err(|e| format!("Invalid slave version requirement {}: {}", requirement, e))?;
+        let denied = deny
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(|v| Version::parse(v).map_err(|e| format!("Invalid denied slave version {}: {}", v, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { requirement, denied })
+    }
+
+    // Policy file, one directive per line:
+    //   require >=1.0.9, <2
+    //   deny 1.1.0, 1.1.1
+    // Several require lines must all hold; '#' starts a comment.
+    pub fn parse_file(content: &str) -> Result<Self, String> {
+        let mut requirements = Vec::new();
+        let mut denied = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some(("require", value)) => requirements.push(value.trim()),
+                Some(("deny", value)) => denied.push(value.trim()),
+                _ => return Err(format!("Invalid slave version policy line: {}", line)),
+            }
+        }
+
+        let requirement = match requirements.is_empty() {
+            true => DEFAULT_SLAVE_VERSION_REQ.to_string(),
+            false => requirements.join(", "),
+        };
+        Self::parse(&requirement, &denied.join(","))
+    }
+
+    pub fn check(&self, version: &str) -> Result<(), String> {
+        let parsed =
+            Version::parse(version.trim()).map_err(|_| format!("version {} is not a semantic version", version))?;
+        if self.denied.contains(&parsed) {
+            return Err(format!("version {} is denied", version));
+        }
+        if !self.requirement.matches(&parsed) {
+            return Err(format!("version {} does not satisfy {}", version, self.requirement));
+        }
+        Ok(())
+    }
+}
+
+// The active policy, optionally backed by a file that is reloaded whenever it
+// changes. A reload only affects slaves registering afterwards.
+pub struct VersionPolicyStore {
+    path: Option<String>,
+    policy: RwLock<Arc<VersionPolicy>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl VersionPolicyStore {
+    pub fn new(policy: VersionPolicy) -> Self {
+        Self {
+            path: None,
+            policy: RwLock::new(Arc::new(policy)),
+            modified: RwLock::new(None),
+        }
+    }
+
+    pub fn from_file(path: &str) -> std::io::Result<Self> {
+        let store = Self {
+            path: Some(path.to_string()),
+            policy: RwLock::new(Arc::new(VersionPolicy::parse(DEFAULT_SLAVE_VERSION_REQ, "").unwrap())),
+            modified: RwLock::new(None),
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    pub fn current(&self) -> Arc<VersionPolicy> {
+        Arc::clone(&self.policy.read().unwrap())
+    }
+
+    pub fn reload(&self) -> std::io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let modified = std::fs::metadata(path)?.modified().ok();
+        let content = std::fs::read_to_string(path)?;
+        let policy =
+            VersionPolicy::parse_file(&content).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        info!("Loaded slave version policy from {}: {:?}", path, policy);
+
+        *self.policy.write().unwrap() = Arc::new(policy);
+        *self.modified.write().unwrap() = modified;
+        Ok(())
+    }
+
+    // Poll the policy file and reload it whenever it changes on disk. A broken
+    // file keeps the previous policy in force.
+    pub fn spawn_reloader(self: &Arc<Self>) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let store = Arc::clone(self);
+
+        tokio::spawn(async move {
+            let mut ticker = interval(RELOAD_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                if modified.is_none() || modified == *store.modified.read().unwrap() {
+                    continue;
+                }
+                if let Err(e) = store.reload() {
+                    error!("Failed to reload slave version policy {}: {}", path, e);
+                    *store.modified.write().unwrap() = modified;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requirement_and_deny_list() {
+        let policy = VersionPolicy::parse(">=1.0.9, <2", "1.1.0").unwrap();
+        assert!(policy.check("1.0.9").is_ok());
+        assert!(policy.check("1.4.2").is_ok());
+        assert!(policy.check("1.1.0").is_err());
+        assert!(policy.check("1.0.8").is_err());
+        assert!(policy.check("2.0.0").is_err());
+        assert!(policy.check("latest").is_err());
+    }
+
+    #[test]
+    fn policy_file() {
+        let policy = VersionPolicy::parse_file("# fleet\nrequire >=1.2\nrequire <3\ndeny 1.5.0, 2.0.1\n").unwrap();
+        assert!(policy.check("2.9.0").is_ok());
+        assert!(policy.check("1.5.0").is_err());
+        assert!(policy.check("3.0.0").is_err());
+        assert!(VersionPolicy::parse_file("allow 1.0.0").is_err());
+    }
+}